@@ -0,0 +1,4 @@
+-- Deliberately does nothing. The tables may have been created by the Python
+-- tokenserver rather than by this migration, so reverting it must not drop
+-- them and the users and nodes in them. Drop them by hand if need be.
+SELECT 1;
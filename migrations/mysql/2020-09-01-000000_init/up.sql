@@ -0,0 +1,39 @@
+-- The tokenserver tables, as created by the Python tokenserver. They are
+-- only created if missing so that an existing tokenserver database can be
+-- used as is.
+CREATE TABLE IF NOT EXISTS `services` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `service` varchar(30) DEFAULT NULL,
+  `pattern` varchar(128) DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `service` (`service`)
+);
+
+CREATE TABLE IF NOT EXISTS `nodes` (
+  `id` bigint NOT NULL AUTO_INCREMENT,
+  `service` int NOT NULL,
+  `node` varchar(64) NOT NULL,
+  `available` int NOT NULL DEFAULT 0,
+  `current_load` int NOT NULL DEFAULT 0,
+  `capacity` int NOT NULL DEFAULT 0,
+  `downed` int NOT NULL DEFAULT 0,
+  `backoff` int NOT NULL DEFAULT 0,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `unique_idx` (`service`, `node`)
+);
+
+CREATE TABLE IF NOT EXISTS `users` (
+  `uid` bigint NOT NULL AUTO_INCREMENT,
+  `service` int NOT NULL,
+  `email` varchar(255) NOT NULL,
+  `generation` bigint NOT NULL,
+  `client_state` varchar(32) NOT NULL,
+  `created_at` bigint NOT NULL,
+  `replaced_at` bigint DEFAULT NULL,
+  `nodeid` bigint NOT NULL,
+  `keys_changed_at` bigint DEFAULT NULL,
+  PRIMARY KEY (`uid`),
+  KEY `lookup_idx` (`email`, `service`, `created_at`),
+  KEY `replaced_at_idx` (`service`, `replaced_at`),
+  KEY `node_idx` (`nodeid`)
+);
@@ -0,0 +1,3 @@
+-- Deliberately does nothing, like the MySQL migration: the tables may
+-- predate this migration, so reverting it must not drop them.
+SELECT 1;
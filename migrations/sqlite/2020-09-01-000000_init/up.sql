@@ -0,0 +1,34 @@
+-- The tokenserver tables, laid out as in the MySQL migration.
+CREATE TABLE IF NOT EXISTS services (
+  id INTEGER PRIMARY KEY,
+  service VARCHAR(30) DEFAULT NULL UNIQUE,
+  pattern VARCHAR(128) DEFAULT NULL
+);
+
+CREATE TABLE IF NOT EXISTS nodes (
+  id INTEGER PRIMARY KEY,
+  service INTEGER NOT NULL,
+  node VARCHAR(64) NOT NULL,
+  available INTEGER NOT NULL DEFAULT 0,
+  current_load INTEGER NOT NULL DEFAULT 0,
+  capacity INTEGER NOT NULL DEFAULT 0,
+  downed INTEGER NOT NULL DEFAULT 0,
+  backoff INTEGER NOT NULL DEFAULT 0,
+  UNIQUE (service, node)
+);
+
+CREATE TABLE IF NOT EXISTS users (
+  uid INTEGER PRIMARY KEY,
+  service INTEGER NOT NULL,
+  email VARCHAR(255) NOT NULL,
+  generation BIGINT NOT NULL,
+  client_state VARCHAR(32) NOT NULL,
+  created_at BIGINT NOT NULL,
+  replaced_at BIGINT DEFAULT NULL,
+  nodeid BIGINT NOT NULL,
+  keys_changed_at BIGINT DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS lookup_idx ON users (email, service, created_at);
+CREATE INDEX IF NOT EXISTS replaced_at_idx ON users (service, replaced_at);
+CREATE INDEX IF NOT EXISTS node_idx ON users (nodeid);
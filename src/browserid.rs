@@ -0,0 +1,235 @@
+//! BrowserID assertions, as sent by older Firefox clients in an
+//! `Authorization: BrowserID <assertion>` header.
+//!
+//! Assertions are checked by a remote verifier such as
+//! https://verifier.accounts.firefox.com/v2, following the Python
+//! tokenserver's `RemoteVerifier`.
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::oauth::VerifyError;
+use crate::settings::Settings;
+
+/// What a verified assertion says about the user.
+#[derive(Debug)]
+pub struct Assertion {
+    pub email: String,
+    /// The FxA uid, the local part of `email`.
+    pub user: String,
+    pub generation: Option<i64>,
+    pub keys_changed_at: Option<i64>,
+    pub device_id: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BrowserIdVerifier {
+    url: String,
+    audience: String,
+    /// The only issuer whose assertions are accepted.
+    issuer: String,
+    client: reqwest::Client,
+}
+
+/// The body of a verifier response.
+#[derive(Debug, Deserialize)]
+struct VerifyResponse {
+    status: String,
+    reason: Option<String>,
+    email: Option<String>,
+    issuer: Option<String>,
+    #[serde(rename = "idpClaims", default)]
+    idp_claims: IdpClaims,
+}
+
+/// The FxA specific claims of an assertion.
+#[derive(Debug, Default, Deserialize)]
+struct IdpClaims {
+    #[serde(rename = "fxa-generation")]
+    generation: Option<i64>,
+    #[serde(rename = "fxa-keysChangedAt")]
+    keys_changed_at: Option<i64>,
+    #[serde(rename = "fxa-deviceId")]
+    device_id: Option<String>,
+    #[serde(rename = "fxa-tokenVerified")]
+    token_verified: Option<bool>,
+}
+
+impl BrowserIdVerifier {
+    pub fn new(url: &str, audience: &str, issuer: &str, timeout: Duration) -> ApiResult<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid BrowserID client: {}", e)))?;
+        Ok(Self {
+            url: url.to_owned(),
+            audience: audience.to_owned(),
+            issuer: issuer.to_owned(),
+            client,
+        })
+    }
+
+    /// A verifier for the configured `browserid_audience`, if there is one.
+    pub fn from_settings(settings: &Settings) -> ApiResult<Option<Self>> {
+        match settings.browserid_audience {
+            Some(ref audience) => Ok(Some(Self::new(
+                &settings.browserid_verifier_url,
+                audience,
+                &settings.fxa_email_domain,
+                Duration::from_secs(settings.browserid_request_timeout),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn verify(&self, assertion: &str) -> Result<Assertion, VerifyError> {
+        let unavailable = |e: reqwest::Error| VerifyError::RemoteUnavailable(e.to_string());
+        let response: VerifyResponse = self
+            .client
+            .post(&self.url)
+            .json(&serde_json::json!({
+                "assertion": assertion,
+                "audience": self.audience,
+                "trustedIssuers": [self.issuer],
+            }))
+            .send()
+            .await
+            .map_err(unavailable)?
+            .error_for_status()
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+
+        match response.status.as_str() {
+            "okay" => (),
+            "failure" => {
+                let reason = response.reason.unwrap_or_default();
+                return Err(if reason.starts_with("untrusted issuer") {
+                    VerifyError::WrongIssuer
+                } else if reason.starts_with("assertion has expired") {
+                    VerifyError::Expired
+                } else {
+                    debug!("BrowserID assertion rejected: {}", reason);
+                    VerifyError::BadSignature
+                });
+            }
+            status => {
+                return Err(VerifyError::RemoteUnavailable(format!(
+                    "Unexpected verifier status {:?}",
+                    status
+                )))
+            }
+        }
+        if response.issuer.as_deref() != Some(self.issuer.as_str()) {
+            return Err(VerifyError::WrongIssuer);
+        }
+        // Sessions that haven't been confirmed yet can't sync. Older
+        // assertions don't say, and are taken to be confirmed.
+        if response.idp_claims.token_verified == Some(false) {
+            return Err(VerifyError::Unconfirmed);
+        }
+
+        let email = response
+            .email
+            .ok_or_else(|| VerifyError::MalformedClaims("missing email".to_owned()))?;
+        let user = match email.find('@') {
+            Some(at) if at > 0 => email[..at].to_owned(),
+            _ => return Err(VerifyError::MalformedClaims("invalid email".to_owned())),
+        };
+        Ok(Assertion {
+            email,
+            user,
+            generation: response.idp_claims.generation,
+            keys_changed_at: response.idp_claims.keys_changed_at,
+            device_id: response.idp_claims.device_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, Matcher};
+
+    #[actix_rt::test]
+    async fn test_verify() {
+        let verifier = BrowserIdVerifier::new(
+            &format!("{}/v2", mockito::server_url()),
+            "https://token.example.com",
+            "api.accounts.firefox.com",
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        let verified = mock("POST", "/v2")
+            .match_body(Matcher::Json(serde_json::json!({
+                "assertion": "cert~assertion",
+                "audience": "https://token.example.com",
+                "trustedIssuers": ["api.accounts.firefox.com"],
+            })))
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"status": "okay", "email": "deadbeef@api.accounts.firefox.com",
+                    "issuer": "api.accounts.firefox.com", "audience": "https://token.example.com",
+                    "idpClaims": {"fxa-generation": 1234, "fxa-keysChangedAt": 1200,
+                                  "fxa-deviceId": "device", "fxa-tokenVerified": true}}"#,
+            )
+            .create();
+        let assertion = verifier.verify("cert~assertion").await.unwrap();
+        verified.assert();
+        assert_eq!(assertion.email, "deadbeef@api.accounts.firefox.com");
+        assert_eq!(assertion.user, "deadbeef");
+        assert_eq!(assertion.generation, Some(1234));
+        assert_eq!(assertion.keys_changed_at, Some(1200));
+        assert_eq!(assertion.device_id.as_deref(), Some("device"));
+        drop(verified);
+
+        let cases = vec![
+            (
+                200,
+                r#"{"status": "failure", "reason": "assertion has expired"}"#,
+                "expired",
+            ),
+            (
+                200,
+                r#"{"status": "failure", "reason": "untrusted issuer: evil.example.com"}"#,
+                "wrong_issuer",
+            ),
+            (
+                200,
+                r#"{"status": "failure", "reason": "bad signature in chain"}"#,
+                "bad_signature",
+            ),
+            (
+                200,
+                r#"{"status": "okay", "email": "deadbeef@evil.example.com", "issuer": "evil.example.com"}"#,
+                "wrong_issuer",
+            ),
+            (
+                200,
+                r#"{"status": "okay", "email": "deadbeef@api.accounts.firefox.com",
+                    "issuer": "api.accounts.firefox.com", "idpClaims": {"fxa-tokenVerified": false}}"#,
+                "unconfirmed",
+            ),
+            (
+                200,
+                r#"{"status": "okay", "email": "@api.accounts.firefox.com", "issuer": "api.accounts.firefox.com"}"#,
+                "malformed_claims",
+            ),
+            (200, r#"{"status": "potato"}"#, "remote_unavailable"),
+            (200, "<html>", "remote_unavailable"),
+            (503, "", "remote_unavailable"),
+        ];
+        for (status, body, reason) in cases {
+            let response = mock("POST", "/v2")
+                .with_status(status)
+                .with_body(body)
+                .create();
+            let e = verifier.verify("cert~assertion").await.unwrap_err();
+            response.assert();
+            assert_eq!(e.reason(), reason, "{}", body);
+        }
+    }
+}
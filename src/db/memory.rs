@@ -0,0 +1,499 @@
+//! In-process backend for tests and local development, selected with a
+//! `memory://` database_url. Nothing is persisted.
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::future;
+
+use super::{params, results, Db, DbFuture, DbPool, SYNC_SERVICE};
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::settings::Settings;
+
+/// Only this many of a user's most recent records are considered, as with
+/// the SQL backends.
+const MAX_USER_RECORDS: usize = 20;
+
+#[derive(Debug, Default)]
+struct Service {
+    id: i32,
+    service: String,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    id: i64,
+    service: i32,
+    node: String,
+    available: i32,
+    current_load: i32,
+    capacity: i32,
+    downed: i32,
+    backoff: i32,
+}
+
+#[derive(Debug, Default)]
+struct User {
+    uid: i64,
+    service: i32,
+    email: String,
+    generation: i64,
+    client_state: String,
+    created_at: i64,
+    replaced_at: Option<i64>,
+    nodeid: i64,
+    keys_changed_at: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+struct Tables {
+    services: Vec<Service>,
+    nodes: Vec<Node>,
+    users: Vec<User>,
+}
+
+impl Tables {
+    fn insert_user(&mut self, params: params::InsertUser) -> i64 {
+        let uid = self.users.len() as i64 + 1;
+        self.users.push(User {
+            uid,
+            service: params.service_id,
+            email: params.email,
+            generation: params.generation,
+            client_state: params.client_state,
+            created_at: params.created_at,
+            replaced_at: None,
+            nodeid: params.node_id,
+            keys_changed_at: params.keys_changed_at,
+        });
+        uid
+    }
+
+    /// Assign a user to the least loaded node with room for them, releasing
+    /// more capacity if none has any, on demand like the Python tokenserver.
+    fn take_best_node(
+        &mut self,
+        service_id: i32,
+        capacity_release_rate: f64,
+    ) -> ApiResult<results::GetBestNode> {
+        let index = match best_node(&self.nodes, service_id) {
+            Some(index) => index,
+            None => {
+                for node in self.nodes.iter_mut().filter(|n| {
+                    n.service == service_id
+                        && n.available <= 0
+                        && n.capacity > n.current_load
+                        && n.downed == 0
+                }) {
+                    let released = (f64::from(node.capacity) * capacity_release_rate).round();
+                    node.available = (released as i32).min(node.capacity - node.current_load);
+                }
+                best_node(&self.nodes, service_id).ok_or(ApiErrorKind::NoNodeAvailable)?
+            }
+        };
+
+        let node = &mut self.nodes[index];
+        node.current_load += 1;
+        node.available = (node.available - 1).max(0);
+        Ok(results::GetBestNode {
+            id: node.id,
+            node: node.node.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryDbPool {
+    tables: Arc<Mutex<Tables>>,
+    capacity_release_rate: f64,
+}
+
+impl MemoryDbPool {
+    /// Create an empty database with the Sync service, and a node for it
+    /// if `service_entry` is set.
+    pub fn new(settings: &Settings) -> ApiResult<Self> {
+        let pool = Self {
+            tables: Default::default(),
+            capacity_release_rate: settings.capacity_release_rate,
+        };
+        let db = pool.db();
+        let service_id = db.add_service_sync(params::AddService {
+            service: SYNC_SERVICE.to_owned(),
+            pattern: "{node}/1.5/{uid}".to_owned(),
+        })?;
+        if let Some(ref node) = settings.service_entry {
+            db.add_node_sync(params::AddNode {
+                service_id,
+                node: node.clone(),
+                capacity: i32::MAX,
+                available: i32::MAX,
+                ..Default::default()
+            })?;
+        }
+        Ok(pool)
+    }
+
+    fn db(&self) -> MemoryDb {
+        MemoryDb {
+            tables: self.tables.clone(),
+            capacity_release_rate: self.capacity_release_rate,
+        }
+    }
+}
+
+impl DbPool for MemoryDbPool {
+    fn get(&self) -> ApiResult<Box<dyn Db>> {
+        Ok(Box::new(self.db()))
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+
+    /// There are no connections to hold.
+    fn state(&self) -> results::PoolState {
+        results::PoolState::default()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryDb {
+    tables: Arc<Mutex<Tables>>,
+    capacity_release_rate: f64,
+}
+
+macro_rules! sync_db_method {
+    ($name:ident, $sync_name:ident, $type:ident) => {
+        sync_db_method!($name, $sync_name, $type, results::$type);
+    };
+    ($name:ident, $sync_name:ident, $type:ident, $result:ty) => {
+        fn $name(&self, params: params::$type) -> DbFuture<$result> {
+            Box::pin(future::ready(self.$sync_name(params)))
+        }
+    };
+}
+
+impl Db for MemoryDb {
+    sync_db_method!(get_service_id, get_service_id_sync, GetServiceId);
+    sync_db_method!(add_service, add_service_sync, AddService);
+    sync_db_method!(get_user, get_user_sync, GetUser, Option<results::GetUser>);
+    sync_db_method!(allocate_user, allocate_user_sync, AllocateUser);
+    sync_db_method!(insert_user, insert_user_sync, InsertUser);
+    sync_db_method!(update_user, update_user_sync, UpdateUser);
+    sync_db_method!(
+        replace_user_record,
+        replace_user_record_sync,
+        ReplaceUserRecord
+    );
+    sync_db_method!(replace_user, replace_user_sync, ReplaceUser);
+    sync_db_method!(replace_users, replace_users_sync, ReplaceUsers);
+    sync_db_method!(get_best_node, get_best_node_sync, GetBestNode);
+    sync_db_method!(add_node, add_node_sync, AddNode);
+    sync_db_method!(get_node_id, get_node_id_sync, GetNodeId);
+    sync_db_method!(get_node_capacity, get_node_capacity_sync, GetNodeCapacity);
+
+    fn check(&self) -> DbFuture<results::Check> {
+        Box::pin(future::ready(self.lock().map(|_| ())))
+    }
+
+    #[cfg(test)]
+    fn get_user_records(&self, params: params::GetUser) -> DbFuture<Vec<(i64, Option<i64>)>> {
+        let records = self.lock().map(|tables| {
+            let mut records: Vec<&User> = tables
+                .users
+                .iter()
+                .filter(|u| u.service == params.service_id && u.email == params.email)
+                .collect();
+            records.sort_by_key(|u| std::cmp::Reverse((u.created_at, u.uid)));
+            records.iter().map(|u| (u.uid, u.replaced_at)).collect()
+        });
+        Box::pin(future::ready(records))
+    }
+}
+
+impl MemoryDb {
+    fn lock(&self) -> ApiResult<MutexGuard<'_, Tables>> {
+        self.tables
+            .lock()
+            .map_err(|_| ApiErrorKind::Internal("Memory db lock poisoned".to_owned()).into())
+    }
+
+    fn get_service_id_sync(
+        &self,
+        params: params::GetServiceId,
+    ) -> ApiResult<results::GetServiceId> {
+        self.lock()?
+            .services
+            .iter()
+            .find(|s| s.service == params.service)
+            .map(|s| s.id)
+            .ok_or_else(|| diesel::result::Error::NotFound.into())
+    }
+
+    fn add_service_sync(&self, params: params::AddService) -> ApiResult<results::AddService> {
+        let mut tables = self.lock()?;
+        let id = tables.services.len() as i32 + 1;
+        tables.services.push(Service {
+            id,
+            service: params.service,
+        });
+        Ok(id)
+    }
+
+    fn get_user_sync(&self, params: params::GetUser) -> ApiResult<Option<results::GetUser>> {
+        let mut tables = self.lock()?;
+        let mut records: Vec<&User> = tables
+            .users
+            .iter()
+            .filter(|u| u.service == params.service_id && u.email == params.email)
+            .collect();
+        records.sort_by_key(|u| std::cmp::Reverse((u.created_at, u.uid)));
+        let mut records = records.into_iter().take(MAX_USER_RECORDS);
+
+        let current = match records.next() {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let mut user = results::GetUser {
+            uid: current.uid,
+            email: params.email,
+            node: tables
+                .nodes
+                .iter()
+                .find(|n| n.id == current.nodeid)
+                .map(|n| n.node.clone()),
+            generation: current.generation,
+            keys_changed_at: current.keys_changed_at,
+            client_state: current.client_state.clone(),
+            old_client_states: vec![],
+            created_at: current.created_at,
+            replaced_at: current.replaced_at,
+            first_seen_at: current.created_at,
+        };
+        let mut stale = vec![];
+        for old in records {
+            if old.client_state != user.client_state
+                && !user.old_client_states.contains(&old.client_state)
+            {
+                user.old_client_states.push(old.client_state.clone());
+            }
+            // Older records should all have been replaced, but may not have
+            // been if two requests raced to create the user.
+            if old.replaced_at.is_none() {
+                stale.push((old.uid, user.first_seen_at));
+            }
+            user.first_seen_at = old.created_at;
+        }
+        for (uid, replaced_at) in stale {
+            if let Some(record) = tables.users.iter_mut().find(|u| u.uid == uid) {
+                record.replaced_at = Some(replaced_at);
+            }
+        }
+        Ok(Some(user))
+    }
+
+    fn allocate_user_sync(&self, params: params::AllocateUser) -> ApiResult<results::AllocateUser> {
+        let mut tables = self.lock()?;
+        let node = tables.take_best_node(params.service_id, self.capacity_release_rate)?;
+        let uid = tables.insert_user(params::InsertUser {
+            service_id: params.service_id,
+            email: params.email,
+            generation: params.generation,
+            client_state: params.client_state,
+            keys_changed_at: params.keys_changed_at,
+            created_at: params.created_at,
+            node_id: node.id,
+        });
+        Ok(results::AllocateUser {
+            uid,
+            node: node.node,
+            created_at: params.created_at,
+        })
+    }
+
+    fn insert_user_sync(&self, params: params::InsertUser) -> ApiResult<results::InsertUser> {
+        Ok(self.lock()?.insert_user(params))
+    }
+
+    fn update_user_sync(&self, params: params::UpdateUser) -> ApiResult<results::UpdateUser> {
+        let mut tables = self.lock()?;
+        let users = tables.users.iter_mut().filter(|u| {
+            u.service == params.service_id && u.email == params.email && u.replaced_at.is_none()
+        });
+        for user in users {
+            let generation = params.generation.unwrap_or(user.generation);
+            let keys_changed_at = params.keys_changed_at.or(user.keys_changed_at);
+            if user.generation <= generation
+                && user.keys_changed_at.unwrap_or(0) <= keys_changed_at.unwrap_or(0)
+            {
+                user.generation = generation;
+                user.keys_changed_at = keys_changed_at;
+            }
+        }
+        Ok(())
+    }
+
+    fn replace_user_record_sync(
+        &self,
+        params: params::ReplaceUserRecord,
+    ) -> ApiResult<results::ReplaceUserRecord> {
+        let mut tables = self.lock()?;
+        let service_id = params.service_id;
+        let node = match params.node {
+            Some(node) => results::GetBestNode {
+                id: tables
+                    .nodes
+                    .iter()
+                    .find(|n| n.service == service_id && n.node == node)
+                    .map(|n| n.id)
+                    .ok_or(diesel::result::Error::NotFound)?,
+                node,
+            },
+            None => tables.take_best_node(service_id, self.capacity_release_rate)?,
+        };
+        let email = params.email;
+        let uid = tables.insert_user(params::InsertUser {
+            service_id,
+            email: email.clone(),
+            generation: params.generation,
+            client_state: params.client_state,
+            keys_changed_at: params.keys_changed_at,
+            created_at: params.created_at,
+            node_id: node.id,
+        });
+        let others = tables.users.iter_mut().filter(|u| {
+            u.service == service_id && u.email == email && u.replaced_at.is_none() && u.uid != uid
+        });
+        for user in others {
+            user.replaced_at = Some(params.created_at);
+        }
+        Ok(results::ReplaceUserRecord {
+            uid,
+            node: node.node,
+            created_at: params.created_at,
+        })
+    }
+
+    fn replace_user_sync(&self, params: params::ReplaceUser) -> ApiResult<results::ReplaceUser> {
+        let mut tables = self.lock()?;
+        let users = tables
+            .users
+            .iter_mut()
+            .filter(|u| u.service == params.service_id && u.uid == params.uid);
+        for user in users {
+            user.replaced_at = Some(params.replaced_at);
+        }
+        Ok(())
+    }
+
+    fn replace_users_sync(&self, params: params::ReplaceUsers) -> ApiResult<results::ReplaceUsers> {
+        let mut tables = self.lock()?;
+        let users = tables.users.iter_mut().filter(|u| {
+            u.service == params.service_id
+                && u.email == params.email
+                && u.replaced_at.is_none()
+                && u.created_at < params.replaced_at
+        });
+        for user in users {
+            user.replaced_at = Some(params.replaced_at);
+        }
+        Ok(())
+    }
+
+    fn get_best_node_sync(&self, params: params::GetBestNode) -> ApiResult<results::GetBestNode> {
+        self.lock()?
+            .take_best_node(params.service_id, self.capacity_release_rate)
+    }
+
+    fn add_node_sync(&self, params: params::AddNode) -> ApiResult<results::AddNode> {
+        let mut tables = self.lock()?;
+        let id = tables.nodes.len() as i64 + 1;
+        tables.nodes.push(Node {
+            id,
+            service: params.service_id,
+            node: params.node,
+            available: params.available,
+            current_load: params.current_load,
+            capacity: params.capacity,
+            downed: params.downed,
+            backoff: params.backoff,
+        });
+        Ok(id)
+    }
+
+    fn get_node_id_sync(&self, params: params::GetNodeId) -> ApiResult<results::GetNodeId> {
+        self.lock()?
+            .nodes
+            .iter()
+            .find(|n| n.service == params.service_id && n.node == params.node)
+            .map(|n| n.id)
+            .ok_or_else(|| diesel::result::Error::NotFound.into())
+    }
+
+    fn get_node_capacity_sync(
+        &self,
+        params: params::GetNodeCapacity,
+    ) -> ApiResult<results::GetNodeCapacity> {
+        let tables = self.lock()?;
+        let nodes = tables
+            .nodes
+            .iter()
+            .filter(|n| n.service == params.service_id && n.downed == 0);
+        let mut capacity = results::GetNodeCapacity::default();
+        for node in nodes {
+            capacity.capacity += i64::from(node.capacity);
+            capacity.current_load += i64::from(node.current_load);
+            capacity.available += i64::from(node.available);
+        }
+        Ok(capacity)
+    }
+}
+
+/// The index of the least loaded node with room for another user.
+fn best_node(nodes: &[Node], service_id: i32) -> Option<usize> {
+    let load = |n: &Node| f64::from(n.current_load) / f64::from(n.capacity);
+    nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, n)| {
+            n.service == service_id
+                && n.available > 0
+                && n.capacity > n.current_load
+                && n.downed == 0
+                && n.backoff == 0
+        })
+        .min_by(|(_, a), (_, b)| load(a).partial_cmp(&load(b)).unwrap_or(Ordering::Equal))
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::db_tests;
+
+    #[actix_rt::test]
+    async fn test_memory() {
+        let pool = MemoryDbPool::new(&Settings::default()).unwrap();
+        db_tests(Box::new(pool)).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_service_entry() {
+        let pool = MemoryDbPool::new(&Settings {
+            service_entry: Some("https://example.com".to_owned()),
+            ..Settings::default()
+        })
+        .unwrap();
+        let db = pool.get().unwrap();
+        let service_id = db
+            .get_service_id(params::GetServiceId {
+                service: SYNC_SERVICE.to_owned(),
+            })
+            .await
+            .unwrap();
+        let node = db
+            .get_best_node(params::GetBestNode { service_id })
+            .await
+            .unwrap();
+        assert_eq!(node.node, "https://example.com");
+    }
+}
@@ -0,0 +1,102 @@
+//! Storage of users and the nodes they are assigned to.
+pub mod memory;
+pub mod mysql;
+pub mod params;
+pub mod results;
+mod sql;
+pub mod sqlite;
+#[cfg(test)]
+mod tests;
+
+use std::fmt::Debug;
+
+use futures::future::LocalBoxFuture;
+
+use url::Url;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::settings::Settings;
+
+/// The name of the Sync service in the `services` table.
+pub const SYNC_SERVICE: &str = "sync-1.5";
+
+pub type DbFuture<T> = LocalBoxFuture<'static, ApiResult<T>>;
+
+pub trait DbPool: Sync + Send + Debug {
+    fn get(&self) -> ApiResult<Box<dyn Db>>;
+
+    fn box_clone(&self) -> Box<dyn DbPool>;
+
+    /// The connections held open, for the pool gauges.
+    fn state(&self) -> results::PoolState;
+}
+
+impl Clone for Box<dyn DbPool> {
+    fn clone(&self) -> Box<dyn DbPool> {
+        self.box_clone()
+    }
+}
+
+pub trait Db: Debug {
+    fn get_service_id(&self, params: params::GetServiceId) -> DbFuture<results::GetServiceId>;
+
+    fn add_service(&self, params: params::AddService) -> DbFuture<results::AddService>;
+
+    /// The most recent record for a user, if any.
+    fn get_user(&self, params: params::GetUser) -> DbFuture<Option<results::GetUser>>;
+
+    fn allocate_user(&self, params: params::AllocateUser) -> DbFuture<results::AllocateUser>;
+
+    /// Returns the new record's uid.
+    fn insert_user(&self, params: params::InsertUser) -> DbFuture<results::InsertUser>;
+
+    fn update_user(&self, params: params::UpdateUser) -> DbFuture<results::UpdateUser>;
+
+    /// Create a user's new current record and retire their others, at once
+    /// so that the user is never left with two current records.
+    fn replace_user_record(
+        &self,
+        params: params::ReplaceUserRecord,
+    ) -> DbFuture<results::ReplaceUserRecord>;
+
+    fn replace_user(&self, params: params::ReplaceUser) -> DbFuture<results::ReplaceUser>;
+
+    fn replace_users(&self, params: params::ReplaceUsers) -> DbFuture<results::ReplaceUsers>;
+
+    /// Pick the node a new user should be assigned to, counting the user
+    /// against its load.
+    fn get_best_node(&self, params: params::GetBestNode) -> DbFuture<results::GetBestNode>;
+
+    fn add_node(&self, params: params::AddNode) -> DbFuture<results::AddNode>;
+
+    fn get_node_id(&self, params: params::GetNodeId) -> DbFuture<results::GetNodeId>;
+
+    fn get_node_capacity(
+        &self,
+        params: params::GetNodeCapacity,
+    ) -> DbFuture<results::GetNodeCapacity>;
+
+    /// Check the database can be queried, for the heartbeat.
+    fn check(&self) -> DbFuture<results::Check>;
+
+    /// The uid and `replaced_at` of a user's records, newest first, for tests
+    /// to check which are current.
+    #[cfg(test)]
+    fn get_user_records(&self, params: params::GetUser) -> DbFuture<Vec<(i64, Option<i64>)>>;
+}
+
+/// Create the pool for the database configured in `settings`.
+pub fn pool_from_settings(settings: &Settings) -> ApiResult<Box<dyn DbPool>> {
+    let url = Url::parse(&settings.database_url)
+        .map_err(|e| ApiErrorKind::Internal(format!("Invalid database_url: {}", e)))?;
+    Ok(match url.scheme() {
+        "memory" => Box::new(memory::MemoryDbPool::new(settings)?),
+        "mysql" => Box::new(mysql::MysqlDbPool::from_settings(settings)?),
+        "sqlite" => Box::new(sqlite::SqliteDbPool::from_settings(settings)?),
+        scheme => {
+            return Err(
+                ApiErrorKind::Internal(format!("Unsupported database_url: {}://", scheme)).into(),
+            )
+        }
+    })
+}
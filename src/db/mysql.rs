@@ -0,0 +1,91 @@
+//! MySQL backend, compatible with the Python tokenserver's database.
+use diesel::{
+    mysql::{Mysql, MysqlConnection},
+    r2d2::{ConnectionManager, Pool},
+    Connection,
+};
+
+use super::sql::{SqlConnection, SqlDbPool};
+use crate::error::ApiResult;
+use crate::settings::Settings;
+
+embed_migrations!("migrations/mysql");
+
+/// Create the tokenserver tables in the database at `database_url` if they
+/// don't exist yet.
+pub fn run_embedded_migrations(database_url: &str) -> ApiResult<()> {
+    let conn = MysqlConnection::establish(database_url)?;
+    embedded_migrations::run(&conn)?;
+    Ok(())
+}
+
+pub type MysqlDbPool = SqlDbPool<MysqlConnection>;
+
+impl MysqlDbPool {
+    pub fn from_settings(settings: &Settings) -> ApiResult<Self> {
+        if settings.run_migrations {
+            run_embedded_migrations(&settings.database_url)?;
+        }
+        let manager = ConnectionManager::<MysqlConnection>::new(settings.database_url.as_str());
+        let pool = Pool::builder()
+            .max_size(settings.database_pool_max_size)
+            .build(manager)?;
+        Ok(Self::new(pool, settings.capacity_release_rate))
+    }
+}
+
+impl SqlConnection for MysqlConnection {
+    type Db = Mysql;
+
+    const LAST_INSERT_ID: &'static str = "SELECT LAST_INSERT_ID() AS id";
+
+    // The candidate rows stay locked until the transaction commits.
+    const SELECT_BEST_NODE: &'static str = "SELECT id, node FROM nodes
+          WHERE service = ? AND available > 0 AND capacity > current_load
+            AND downed = 0 AND backoff = 0
+          ORDER BY current_load / capacity
+          LIMIT 1
+          FOR UPDATE";
+
+    const ADD_NODE_LOAD: &'static str = "UPDATE nodes
+            SET current_load = current_load + 1, available = GREATEST(available - 1, 0)
+          WHERE id = ?";
+
+    const RELEASE_CAPACITY: &'static str = "UPDATE nodes
+            SET available = LEAST(ROUND(capacity * ?), capacity - current_load)
+          WHERE service = ? AND available <= 0 AND capacity > current_load AND downed = 0";
+
+    const SELECT_NODE_CAPACITY: &'static str =
+        "SELECT CAST(COALESCE(SUM(capacity), 0) AS SIGNED) AS capacity,
+               CAST(COALESCE(SUM(current_load), 0) AS SIGNED) AS current_load,
+               CAST(COALESCE(SUM(available), 0) AS SIGNED) AS available
+          FROM nodes
+         WHERE service = ? AND downed = 0";
+
+    fn write_transaction<T, F>(&self, f: F) -> ApiResult<T>
+    where
+        F: FnOnce() -> ApiResult<T>,
+    {
+        self.transaction(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::db_tests;
+
+    // These need a MySQL server: set TOKENSERV_DATABASE_URL and run with
+    // `cargo test -- --ignored`.
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_mysql() {
+        let settings = Settings::with_env_and_config_file(&None).unwrap();
+        let pool = MysqlDbPool::from_settings(&Settings {
+            run_migrations: true,
+            ..settings
+        })
+        .unwrap();
+        db_tests(Box::new(pool)).await;
+    }
+}
@@ -0,0 +1,107 @@
+//! Parameter types for database methods.
+
+#[derive(Clone, Debug, Default)]
+pub struct GetServiceId {
+    pub service: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AddService {
+    pub service: String,
+    pub pattern: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GetUser {
+    pub service_id: i32,
+    pub email: String,
+}
+
+/// Create a new user record on the best available node.
+#[derive(Clone, Debug, Default)]
+pub struct AllocateUser {
+    pub service_id: i32,
+    pub email: String,
+    pub generation: i64,
+    pub client_state: String,
+    pub keys_changed_at: Option<i64>,
+    pub created_at: i64,
+}
+
+/// Create a new user record on a given node.
+#[derive(Clone, Debug, Default)]
+pub struct InsertUser {
+    pub service_id: i32,
+    pub email: String,
+    pub generation: i64,
+    pub client_state: String,
+    pub keys_changed_at: Option<i64>,
+    pub created_at: i64,
+    pub node_id: i64,
+}
+
+/// Bump the generation and/or keys_changed_at of the current user record.
+/// Values lower than those already stored are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct UpdateUser {
+    pub service_id: i32,
+    pub email: String,
+    pub generation: Option<i64>,
+    pub keys_changed_at: Option<i64>,
+}
+
+/// Create a new current record for a user, on `node` if given and on the
+/// best available node otherwise, and mark every other record of theirs as
+/// replaced.
+#[derive(Clone, Debug, Default)]
+pub struct ReplaceUserRecord {
+    pub service_id: i32,
+    pub email: String,
+    pub generation: i64,
+    pub client_state: String,
+    pub keys_changed_at: Option<i64>,
+    pub created_at: i64,
+    pub node: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplaceUser {
+    pub service_id: i32,
+    pub uid: i64,
+    pub replaced_at: i64,
+}
+
+/// Mark every record for a user created before `replaced_at` as replaced.
+#[derive(Clone, Debug, Default)]
+pub struct ReplaceUsers {
+    pub service_id: i32,
+    pub email: String,
+    pub replaced_at: i64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GetBestNode {
+    pub service_id: i32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AddNode {
+    pub service_id: i32,
+    pub node: String,
+    pub capacity: i32,
+    pub available: i32,
+    pub current_load: i32,
+    pub downed: i32,
+    pub backoff: i32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GetNodeId {
+    pub service_id: i32,
+    pub node: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GetNodeCapacity {
+    pub service_id: i32,
+}
@@ -0,0 +1,60 @@
+//! Result types for database methods.
+
+pub type GetServiceId = i32;
+pub type AddService = i32;
+pub type InsertUser = i64;
+pub type UpdateUser = ();
+pub type ReplaceUser = ();
+pub type ReplaceUsers = ();
+pub type AddNode = i64;
+pub type GetNodeId = i64;
+pub type Check = ();
+
+/// The current record for a user, along with what is known of their
+/// previous records.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GetUser {
+    pub uid: i64,
+    pub email: String,
+    /// The node the user is assigned to, if it still exists
+    pub node: Option<String>,
+    pub generation: i64,
+    pub keys_changed_at: Option<i64>,
+    pub client_state: String,
+    /// Client states of previous records that differ from the current one
+    pub old_client_states: Vec<String>,
+    pub created_at: i64,
+    pub replaced_at: Option<i64>,
+    /// When the oldest known record for the user was created
+    pub first_seen_at: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllocateUser {
+    pub uid: i64,
+    pub node: String,
+    pub created_at: i64,
+}
+
+pub type ReplaceUserRecord = AllocateUser;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GetBestNode {
+    pub id: i64,
+    pub node: String,
+}
+
+/// The capacity of a service's nodes that aren't down, summed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GetNodeCapacity {
+    pub capacity: i64,
+    pub current_load: i64,
+    pub available: i64,
+}
+
+/// The connections a pool holds open.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolState {
+    pub connections: u32,
+    pub idle_connections: u32,
+}
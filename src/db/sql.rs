@@ -0,0 +1,700 @@
+//! The queries and transactions shared by the MySQL and SQLite backends.
+//!
+//! Each backend only supplies its connection setup and, through
+//! `SqlConnection`, the few statements whose SQL differs between the two.
+use std::fmt;
+
+use actix_web::web;
+use diesel::{
+    backend::Backend,
+    deserialize::QueryableByName,
+    r2d2::{ConnectionManager, Pool},
+    sql_query,
+    sql_types::{Bigint, Double, HasSqlType, Integer, Nullable, Text},
+    Connection, OptionalExtension, RunQueryDsl,
+};
+use futures::future::TryFutureExt;
+
+use super::{params, results, Db, DbFuture, DbPool, SYNC_SERVICE};
+use crate::error::{ApiErrorKind, ApiResult};
+
+/// Only this many of a user's most recent records are considered.
+const MAX_USER_RECORDS: i64 = 20;
+
+/// The SQL types the shared queries bind.
+pub trait SqlBackend:
+    Backend + HasSqlType<Integer> + HasSqlType<Bigint> + HasSqlType<Double> + HasSqlType<Text>
+{
+}
+
+impl<DB> SqlBackend for DB where
+    DB: Backend + HasSqlType<Integer> + HasSqlType<Bigint> + HasSqlType<Double> + HasSqlType<Text>
+{
+}
+
+/// A connection the shared queries can run on, along with the statements
+/// its dialect needs written differently.
+pub trait SqlConnection:
+    Connection<Backend = <Self as SqlConnection>::Db> + Send + 'static
+{
+    type Db: SqlBackend;
+
+    /// Selects the id of the row just inserted on this connection, as `id`.
+    const LAST_INSERT_ID: &'static str;
+
+    /// Selects the `id` and `node` of the least loaded node of a service
+    /// with room for another user.
+    const SELECT_BEST_NODE: &'static str;
+
+    /// Counts a user against the load of the node with the given id.
+    const ADD_NODE_LOAD: &'static str;
+
+    /// Makes the given fraction of each full node's capacity available
+    /// again, up to its free space.
+    const RELEASE_CAPACITY: &'static str;
+
+    /// Selects the summed `capacity`, `current_load` and `available` of a
+    /// service's nodes that aren't down, as `Bigint`s.
+    const SELECT_NODE_CAPACITY: &'static str;
+
+    /// Run `f` in a transaction that concurrent node assignments wait on, so
+    /// they can't overfill a node.
+    fn write_transaction<T, F>(&self, f: F) -> ApiResult<T>
+    where
+        F: FnOnce() -> ApiResult<T>;
+}
+
+#[derive(QueryableByName)]
+pub struct RowId {
+    #[sql_type = "Bigint"]
+    id: i64,
+}
+
+#[derive(QueryableByName)]
+pub struct ServiceId {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+#[derive(QueryableByName)]
+pub struct UserRecord {
+    #[sql_type = "Bigint"]
+    uid: i64,
+    #[sql_type = "Nullable<Text>"]
+    node: Option<String>,
+    #[sql_type = "Bigint"]
+    generation: i64,
+    #[sql_type = "Nullable<Bigint>"]
+    keys_changed_at: Option<i64>,
+    #[sql_type = "Text"]
+    client_state: String,
+    #[sql_type = "Bigint"]
+    created_at: i64,
+    #[sql_type = "Nullable<Bigint>"]
+    replaced_at: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+pub struct Node {
+    #[sql_type = "Bigint"]
+    id: i64,
+    #[sql_type = "Text"]
+    node: String,
+}
+
+#[derive(QueryableByName)]
+pub struct NodeCapacity {
+    #[sql_type = "Bigint"]
+    capacity: i64,
+    #[sql_type = "Bigint"]
+    current_load: i64,
+    #[sql_type = "Bigint"]
+    available: i64,
+}
+
+pub struct SqlDbPool<C: SqlConnection> {
+    pool: Pool<ConnectionManager<C>>,
+    capacity_release_rate: f64,
+}
+
+// Derived, `Clone` would need the connection to be `Clone` too.
+impl<C: SqlConnection> Clone for SqlDbPool<C> {
+    fn clone(&self) -> Self {
+        Self::new(self.pool.clone(), self.capacity_release_rate)
+    }
+}
+
+impl<C: SqlConnection> SqlDbPool<C> {
+    pub fn new(pool: Pool<ConnectionManager<C>>, capacity_release_rate: f64) -> Self {
+        Self {
+            pool,
+            capacity_release_rate,
+        }
+    }
+}
+
+impl<C> DbPool for SqlDbPool<C>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+    ServiceId: QueryableByName<C::Db>,
+    UserRecord: QueryableByName<C::Db>,
+    Node: QueryableByName<C::Db>,
+    NodeCapacity: QueryableByName<C::Db>,
+{
+    fn get(&self) -> ApiResult<Box<dyn Db>> {
+        Ok(Box::new(SqlDb {
+            pool: self.pool.clone(),
+            capacity_release_rate: self.capacity_release_rate,
+        }))
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+
+    fn state(&self) -> results::PoolState {
+        let state = self.pool.state();
+        results::PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        }
+    }
+}
+
+impl<C: SqlConnection> fmt::Debug for SqlDbPool<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqlDbPool")
+            .field("state", &self.pool.state())
+            .finish()
+    }
+}
+
+/// Queries run on actix's blocking thread pool, each checking a connection
+/// out of the pool for its duration.
+pub struct SqlDb<C: SqlConnection> {
+    pool: Pool<ConnectionManager<C>>,
+    capacity_release_rate: f64,
+}
+
+impl<C: SqlConnection> SqlDb<C> {
+    fn run<T, F>(&self, f: F) -> DbFuture<T>
+    where
+        F: FnOnce(&C) -> ApiResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        Box::pin(
+            web::block(move || {
+                let conn = pool.get()?;
+                f(&conn)
+            })
+            .map_err(Into::into),
+        )
+    }
+}
+
+impl<C: SqlConnection> fmt::Debug for SqlDb<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqlDb").finish()
+    }
+}
+
+macro_rules! blocking_db_method {
+    ($name:ident, $type:ident) => {
+        blocking_db_method!($name, $type, results::$type);
+    };
+    ($name:ident, $type:ident, $result:ty) => {
+        fn $name(&self, params: params::$type) -> DbFuture<$result> {
+            self.run(move |conn| $name(conn, params))
+        }
+    };
+}
+
+impl<C> Db for SqlDb<C>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+    ServiceId: QueryableByName<C::Db>,
+    UserRecord: QueryableByName<C::Db>,
+    Node: QueryableByName<C::Db>,
+    NodeCapacity: QueryableByName<C::Db>,
+{
+    blocking_db_method!(get_service_id, GetServiceId);
+    blocking_db_method!(add_service, AddService);
+    blocking_db_method!(get_user, GetUser, Option<results::GetUser>);
+
+    fn allocate_user(&self, params: params::AllocateUser) -> DbFuture<results::AllocateUser> {
+        let capacity_release_rate = self.capacity_release_rate;
+        self.run(move |conn| allocate_user(conn, params, capacity_release_rate))
+    }
+
+    blocking_db_method!(insert_user, InsertUser);
+    blocking_db_method!(update_user, UpdateUser);
+
+    fn replace_user_record(
+        &self,
+        params: params::ReplaceUserRecord,
+    ) -> DbFuture<results::ReplaceUserRecord> {
+        let capacity_release_rate = self.capacity_release_rate;
+        self.run(move |conn| replace_user_record(conn, params, capacity_release_rate))
+    }
+
+    blocking_db_method!(replace_user, ReplaceUser);
+    blocking_db_method!(replace_users, ReplaceUsers);
+
+    fn get_best_node(&self, params: params::GetBestNode) -> DbFuture<results::GetBestNode> {
+        let capacity_release_rate = self.capacity_release_rate;
+        self.run(move |conn| get_best_node(conn, params, capacity_release_rate))
+    }
+
+    blocking_db_method!(add_node, AddNode);
+    blocking_db_method!(get_node_id, GetNodeId);
+    blocking_db_method!(get_node_capacity, GetNodeCapacity);
+
+    fn check(&self) -> DbFuture<results::Check> {
+        self.run(check)
+    }
+
+    #[cfg(test)]
+    fn get_user_records(&self, params: params::GetUser) -> DbFuture<Vec<(i64, Option<i64>)>> {
+        self.run(move |conn| {
+            Ok(load_user_records(conn, &params)?
+                .into_iter()
+                .map(|record| (record.uid, record.replaced_at))
+                .collect())
+        })
+    }
+}
+
+fn last_insert_id<C>(conn: &C) -> ApiResult<i64>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+{
+    let row = sql_query(C::LAST_INSERT_ID).get_result::<RowId>(conn)?;
+    Ok(row.id)
+}
+
+fn get_service_id<C>(conn: &C, params: params::GetServiceId) -> ApiResult<results::GetServiceId>
+where
+    C: SqlConnection,
+    ServiceId: QueryableByName<C::Db>,
+{
+    let row = sql_query("SELECT id FROM services WHERE service = ?")
+        .bind::<Text, _>(params.service)
+        .get_result::<ServiceId>(conn)?;
+    Ok(row.id)
+}
+
+fn add_service<C>(conn: &C, params: params::AddService) -> ApiResult<results::AddService>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+{
+    sql_query("INSERT INTO services (service, pattern) VALUES (?, ?)")
+        .bind::<Text, _>(params.service)
+        .bind::<Text, _>(params.pattern)
+        .execute(conn)?;
+    Ok(last_insert_id(conn)? as i32)
+}
+
+/// Add the Sync service, and a node for it at `service_entry`, unless they
+/// are there already.
+pub fn add_sync_service<C>(conn: &C, service_entry: Option<&str>) -> ApiResult<()>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+    ServiceId: QueryableByName<C::Db>,
+{
+    let service_id = sql_query("SELECT id FROM services WHERE service = ?")
+        .bind::<Text, _>(SYNC_SERVICE)
+        .get_result::<ServiceId>(conn)
+        .optional()?;
+    let service_id = match service_id {
+        Some(row) => row.id,
+        None => add_service(
+            conn,
+            params::AddService {
+                service: SYNC_SERVICE.to_owned(),
+                pattern: "{node}/1.5/{uid}".to_owned(),
+            },
+        )?,
+    };
+
+    let node = match service_entry {
+        Some(node) => node,
+        None => return Ok(()),
+    };
+    let node_id = sql_query("SELECT id FROM nodes WHERE service = ? AND node = ?")
+        .bind::<Integer, _>(service_id)
+        .bind::<Text, _>(node)
+        .get_result::<RowId>(conn)
+        .optional()?;
+    if node_id.is_none() {
+        add_node(
+            conn,
+            params::AddNode {
+                service_id,
+                node: node.to_owned(),
+                capacity: i32::MAX,
+                available: i32::MAX,
+                ..Default::default()
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// A user's most recent records, newest first.
+fn load_user_records<C>(conn: &C, params: &params::GetUser) -> ApiResult<Vec<UserRecord>>
+where
+    C: SqlConnection,
+    UserRecord: QueryableByName<C::Db>,
+{
+    Ok(sql_query(
+        "SELECT users.uid, nodes.node, users.generation, users.keys_changed_at,
+                users.client_state, users.created_at, users.replaced_at
+           FROM users LEFT OUTER JOIN nodes ON users.nodeid = nodes.id
+          WHERE users.email = ? AND users.service = ?
+          ORDER BY users.created_at DESC, users.uid DESC
+          LIMIT ?",
+    )
+    .bind::<Text, _>(&params.email)
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Bigint, _>(MAX_USER_RECORDS)
+    .load::<UserRecord>(conn)?)
+}
+
+fn get_user<C>(conn: &C, params: params::GetUser) -> ApiResult<Option<results::GetUser>>
+where
+    C: SqlConnection,
+    UserRecord: QueryableByName<C::Db>,
+{
+    let mut records = load_user_records(conn, &params)?.into_iter();
+
+    // The first record is the current one.
+    let current = match records.next() {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let mut user = results::GetUser {
+        uid: current.uid,
+        email: params.email,
+        node: current.node,
+        generation: current.generation,
+        keys_changed_at: current.keys_changed_at,
+        client_state: current.client_state,
+        old_client_states: vec![],
+        created_at: current.created_at,
+        replaced_at: current.replaced_at,
+        first_seen_at: current.created_at,
+    };
+
+    for old in records {
+        if old.client_state != user.client_state
+            && !user.old_client_states.contains(&old.client_state)
+        {
+            user.old_client_states.push(old.client_state);
+        }
+        // Older records should all have been replaced, but may not have
+        // been if two requests raced to create the user.
+        if old.replaced_at.is_none() {
+            replace_user(
+                conn,
+                params::ReplaceUser {
+                    service_id: params.service_id,
+                    uid: old.uid,
+                    replaced_at: user.first_seen_at,
+                },
+            )?;
+        }
+        user.first_seen_at = old.created_at;
+    }
+    Ok(Some(user))
+}
+
+fn allocate_user<C>(
+    conn: &C,
+    params: params::AllocateUser,
+    capacity_release_rate: f64,
+) -> ApiResult<results::AllocateUser>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+    Node: QueryableByName<C::Db>,
+{
+    conn.write_transaction(|| {
+        let node = take_best_node(conn, params.service_id, capacity_release_rate)?;
+        let uid = insert_user(
+            conn,
+            params::InsertUser {
+                service_id: params.service_id,
+                email: params.email,
+                generation: params.generation,
+                client_state: params.client_state,
+                keys_changed_at: params.keys_changed_at,
+                created_at: params.created_at,
+                node_id: node.id,
+            },
+        )?;
+        Ok(results::AllocateUser {
+            uid,
+            node: node.node,
+            created_at: params.created_at,
+        })
+    })
+}
+
+fn insert_user<C>(conn: &C, params: params::InsertUser) -> ApiResult<results::InsertUser>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+{
+    sql_query(
+        "INSERT INTO users
+                (service, email, nodeid, generation, keys_changed_at, client_state,
+                 created_at, replaced_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, NULL)",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(params.email)
+    .bind::<Bigint, _>(params.node_id)
+    .bind::<Bigint, _>(params.generation)
+    .bind::<Nullable<Bigint>, _>(params.keys_changed_at)
+    .bind::<Text, _>(params.client_state)
+    .bind::<Bigint, _>(params.created_at)
+    .execute(conn)?;
+    last_insert_id(conn)
+}
+
+fn replace_user_record<C>(
+    conn: &C,
+    params: params::ReplaceUserRecord,
+    capacity_release_rate: f64,
+) -> ApiResult<results::ReplaceUserRecord>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+    Node: QueryableByName<C::Db>,
+{
+    conn.write_transaction(|| {
+        let node = match params.node {
+            Some(node) => results::GetBestNode {
+                id: get_node_id(
+                    conn,
+                    params::GetNodeId {
+                        service_id: params.service_id,
+                        node: node.clone(),
+                    },
+                )?,
+                node,
+            },
+            None => take_best_node(conn, params.service_id, capacity_release_rate)?,
+        };
+        let uid = insert_user(
+            conn,
+            params::InsertUser {
+                service_id: params.service_id,
+                email: params.email.clone(),
+                generation: params.generation,
+                client_state: params.client_state,
+                keys_changed_at: params.keys_changed_at,
+                created_at: params.created_at,
+                node_id: node.id,
+            },
+        )?;
+        // Records a concurrent request created are retired too: the last
+        // one written wins.
+        sql_query(
+            "UPDATE users SET replaced_at = ?
+              WHERE service = ? AND email = ? AND replaced_at IS NULL AND uid <> ?",
+        )
+        .bind::<Bigint, _>(params.created_at)
+        .bind::<Integer, _>(params.service_id)
+        .bind::<Text, _>(&params.email)
+        .bind::<Bigint, _>(uid)
+        .execute(conn)?;
+        Ok(results::ReplaceUserRecord {
+            uid,
+            node: node.node,
+            created_at: params.created_at,
+        })
+    })
+}
+
+fn update_user<C: SqlConnection>(
+    conn: &C,
+    params: params::UpdateUser,
+) -> ApiResult<results::UpdateUser> {
+    sql_query(
+        "UPDATE users
+            SET generation = COALESCE(?, generation),
+                keys_changed_at = COALESCE(?, keys_changed_at)
+          WHERE service = ? AND email = ?
+            AND generation <= COALESCE(?, generation)
+            AND COALESCE(keys_changed_at, 0) <= COALESCE(?, keys_changed_at, 0)
+            AND replaced_at IS NULL",
+    )
+    .bind::<Nullable<Bigint>, _>(params.generation)
+    .bind::<Nullable<Bigint>, _>(params.keys_changed_at)
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .bind::<Nullable<Bigint>, _>(params.generation)
+    .bind::<Nullable<Bigint>, _>(params.keys_changed_at)
+    .execute(conn)?;
+    Ok(())
+}
+
+fn replace_user<C: SqlConnection>(
+    conn: &C,
+    params: params::ReplaceUser,
+) -> ApiResult<results::ReplaceUser> {
+    sql_query("UPDATE users SET replaced_at = ? WHERE service = ? AND uid = ?")
+        .bind::<Bigint, _>(params.replaced_at)
+        .bind::<Integer, _>(params.service_id)
+        .bind::<Bigint, _>(params.uid)
+        .execute(conn)?;
+    Ok(())
+}
+
+fn replace_users<C: SqlConnection>(
+    conn: &C,
+    params: params::ReplaceUsers,
+) -> ApiResult<results::ReplaceUsers> {
+    sql_query(
+        "UPDATE users SET replaced_at = ?
+          WHERE service = ? AND email = ? AND replaced_at IS NULL AND created_at < ?",
+    )
+    .bind::<Bigint, _>(params.replaced_at)
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .bind::<Bigint, _>(params.replaced_at)
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Assign a user to the least loaded node with room for them, releasing
+/// more capacity if none has any.
+fn get_best_node<C>(
+    conn: &C,
+    params: params::GetBestNode,
+    capacity_release_rate: f64,
+) -> ApiResult<results::GetBestNode>
+where
+    C: SqlConnection,
+    Node: QueryableByName<C::Db>,
+{
+    conn.write_transaction(|| take_best_node(conn, params.service_id, capacity_release_rate))
+}
+
+/// `get_best_node`, for callers already in a `write_transaction`.
+fn take_best_node<C>(
+    conn: &C,
+    service_id: i32,
+    capacity_release_rate: f64,
+) -> ApiResult<results::GetBestNode>
+where
+    C: SqlConnection,
+    Node: QueryableByName<C::Db>,
+{
+    let node = match select_best_node(conn, service_id)? {
+        Some(node) => node,
+        None => {
+            release_capacity(conn, service_id, capacity_release_rate)?;
+            select_best_node(conn, service_id)?.ok_or(ApiErrorKind::NoNodeAvailable)?
+        }
+    };
+
+    sql_query(C::ADD_NODE_LOAD)
+        .bind::<Bigint, _>(node.id)
+        .execute(conn)?;
+
+    Ok(results::GetBestNode {
+        id: node.id,
+        node: node.node,
+    })
+}
+
+fn select_best_node<C>(conn: &C, service_id: i32) -> ApiResult<Option<Node>>
+where
+    C: SqlConnection,
+    Node: QueryableByName<C::Db>,
+{
+    Ok(sql_query(C::SELECT_BEST_NODE)
+        .bind::<Integer, _>(service_id)
+        .get_result::<Node>(conn)
+        .optional()?)
+}
+
+/// Make some capacity available again on the nodes that have run out.
+///
+/// Like the Python tokenserver, this runs on demand when an allocation finds
+/// no room, rather than on a timer, so it happens inside the allocation's
+/// transaction and can't race with it.
+fn release_capacity<C: SqlConnection>(
+    conn: &C,
+    service_id: i32,
+    capacity_release_rate: f64,
+) -> ApiResult<()> {
+    sql_query(C::RELEASE_CAPACITY)
+        .bind::<Double, _>(capacity_release_rate)
+        .bind::<Integer, _>(service_id)
+        .execute(conn)?;
+    Ok(())
+}
+
+fn add_node<C>(conn: &C, params: params::AddNode) -> ApiResult<results::AddNode>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+{
+    sql_query(
+        "INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(params.node)
+    .bind::<Integer, _>(params.available)
+    .bind::<Integer, _>(params.current_load)
+    .bind::<Integer, _>(params.capacity)
+    .bind::<Integer, _>(params.downed)
+    .bind::<Integer, _>(params.backoff)
+    .execute(conn)?;
+    last_insert_id(conn)
+}
+
+fn get_node_id<C>(conn: &C, params: params::GetNodeId) -> ApiResult<results::GetNodeId>
+where
+    C: SqlConnection,
+    RowId: QueryableByName<C::Db>,
+{
+    let row = sql_query("SELECT id FROM nodes WHERE service = ? AND node = ?")
+        .bind::<Integer, _>(params.service_id)
+        .bind::<Text, _>(params.node)
+        .get_result::<RowId>(conn)?;
+    Ok(row.id)
+}
+
+fn get_node_capacity<C>(
+    conn: &C,
+    params: params::GetNodeCapacity,
+) -> ApiResult<results::GetNodeCapacity>
+where
+    C: SqlConnection,
+    NodeCapacity: QueryableByName<C::Db>,
+{
+    let row = sql_query(C::SELECT_NODE_CAPACITY)
+        .bind::<Integer, _>(params.service_id)
+        .get_result::<NodeCapacity>(conn)?;
+    Ok(results::GetNodeCapacity {
+        capacity: row.capacity,
+        current_load: row.current_load,
+        available: row.available,
+    })
+}
+
+fn check<C: SqlConnection>(conn: &C) -> ApiResult<results::Check> {
+    sql_query("SELECT 1").execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! SQLite backend for single-node deployments, selected with a
+//! `sqlite:///path/to/tokenserver.db` database_url.
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{self, ConnectionManager, CustomizeConnection, Pool},
+    sqlite::{Sqlite, SqliteConnection},
+    Connection,
+};
+
+use super::sql::{self, SqlConnection, SqlDbPool};
+use crate::error::ApiResult;
+use crate::settings::Settings;
+
+embed_migrations!("migrations/sqlite");
+
+/// Create the tokenserver tables in the database at `path` if they don't
+/// exist yet.
+pub fn run_embedded_migrations(path: &str) -> ApiResult<()> {
+    let conn = SqliteConnection::establish(path)?;
+    embedded_migrations::run(&conn)?;
+    Ok(())
+}
+
+/// The database file named by a `sqlite://` database_url.
+fn database_path(database_url: &str) -> &str {
+    match database_url.find("://") {
+        Some(i) => &database_url[i + 3..],
+        None => database_url,
+    }
+}
+
+/// Wait for other connections' writes rather than failing with "database
+/// is locked".
+#[derive(Debug)]
+struct BusyTimeout;
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for BusyTimeout {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        conn.batch_execute("PRAGMA busy_timeout = 5000;")
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+pub type SqliteDbPool = SqlDbPool<SqliteConnection>;
+
+impl SqliteDbPool {
+    /// Open the database, creating the file and its tables if needed, along
+    /// with the Sync service and a node at `service_entry`. A self-hosted
+    /// deployment has no other way to create them, so this always runs.
+    pub fn from_settings(settings: &Settings) -> ApiResult<Self> {
+        let path = database_path(&settings.database_url);
+        run_embedded_migrations(path)?;
+        let manager = ConnectionManager::<SqliteConnection>::new(path);
+        let pool = Pool::builder()
+            .max_size(settings.database_pool_max_size)
+            .connection_customizer(Box::new(BusyTimeout))
+            .build(manager)?;
+        sql::add_sync_service(&*pool.get()?, settings.service_entry.as_deref())?;
+        Ok(Self::new(pool, settings.capacity_release_rate))
+    }
+}
+
+impl SqlConnection for SqliteConnection {
+    type Db = Sqlite;
+
+    const LAST_INSERT_ID: &'static str = "SELECT last_insert_rowid() AS id";
+
+    const SELECT_BEST_NODE: &'static str = "SELECT id, node FROM nodes
+          WHERE service = ? AND available > 0 AND capacity > current_load
+            AND downed = 0 AND backoff = 0
+          ORDER BY CAST(current_load AS REAL) / capacity
+          LIMIT 1";
+
+    const ADD_NODE_LOAD: &'static str = "UPDATE nodes
+            SET current_load = current_load + 1, available = MAX(available - 1, 0)
+          WHERE id = ?";
+
+    const RELEASE_CAPACITY: &'static str = "UPDATE nodes
+            SET available = MIN(CAST(ROUND(capacity * ?) AS INTEGER), capacity - current_load)
+          WHERE service = ? AND available <= 0 AND capacity > current_load AND downed = 0";
+
+    const SELECT_NODE_CAPACITY: &'static str =
+        "SELECT CAST(COALESCE(SUM(capacity), 0) AS INTEGER) AS capacity,
+               CAST(COALESCE(SUM(current_load), 0) AS INTEGER) AS current_load,
+               CAST(COALESCE(SUM(available), 0) AS INTEGER) AS available
+          FROM nodes
+         WHERE service = ? AND downed = 0";
+
+    /// `BEGIN IMMEDIATE` takes the write lock up front, so concurrent
+    /// assignments are serialized.
+    fn write_transaction<T, F>(&self, f: F) -> ApiResult<T>
+    where
+        F: FnOnce() -> ApiResult<T>,
+    {
+        self.immediate_transaction(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::db::{params, tests::db_tests, DbPool, SYNC_SERVICE};
+
+    #[test]
+    fn test_database_path() {
+        assert_eq!(
+            database_path("sqlite:///var/lib/tokenserver.db"),
+            "/var/lib/tokenserver.db"
+        );
+        assert_eq!(database_path("sqlite://tokenserver.db"), "tokenserver.db");
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite() {
+        let path = std::env::temp_dir().join(format!("tokenserver-{}.db", Uuid::new_v4()));
+        let pool = SqliteDbPool::from_settings(&Settings {
+            database_url: format!("sqlite://{}", path.display()),
+            ..Settings::default()
+        })
+        .unwrap();
+        db_tests(Box::new(pool)).await;
+        fs::remove_file(path).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_service_entry() {
+        let path = std::env::temp_dir().join(format!("tokenserver-{}.db", Uuid::new_v4()));
+        let settings = Settings {
+            database_url: format!("sqlite://{}", path.display()),
+            service_entry: Some("https://example.com".to_owned()),
+            ..Settings::default()
+        };
+        // Opening the database again doesn't add the node twice.
+        SqliteDbPool::from_settings(&settings).unwrap();
+        let db = SqliteDbPool::from_settings(&settings)
+            .unwrap()
+            .get()
+            .unwrap();
+        let service_id = db
+            .get_service_id(params::GetServiceId {
+                service: SYNC_SERVICE.to_owned(),
+            })
+            .await
+            .unwrap();
+        let capacity = db
+            .get_node_capacity(params::GetNodeCapacity { service_id })
+            .await
+            .unwrap();
+        assert_eq!(capacity.capacity, i64::from(i32::MAX));
+        let node = db
+            .get_best_node(params::GetBestNode { service_id })
+            .await
+            .unwrap();
+        assert_eq!(node.node, "https://example.com");
+        fs::remove_file(path).unwrap();
+    }
+}
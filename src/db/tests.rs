@@ -0,0 +1,445 @@
+//! Tests shared by every database backend.
+use futures::future::join_all;
+use uuid::Uuid;
+
+use super::{params, results, Db, DbPool};
+use crate::error::ApiErrorKind;
+
+/// Run every test against `pool`. Each test works in its own service, so
+/// the database may be shared with other runs.
+pub async fn db_tests(pool: Box<dyn DbPool>) {
+    let db = pool.get().unwrap();
+    db.check().await.unwrap();
+    test_get_service_id(&*db).await;
+    test_allocate_user(&*db).await;
+    test_insert_user(&*db).await;
+    test_update_user(&*db).await;
+    test_replace_users(&*db).await;
+    test_replace_user_record(&*db).await;
+    test_retire_stale_records(&*db).await;
+    test_get_best_node(&*db).await;
+    test_get_node_capacity(&*db).await;
+    test_no_node_available(&*db).await;
+    test_release_capacity(&*db).await;
+    test_concurrent_allocation(&*db).await;
+}
+
+async fn add_service(db: &dyn Db) -> i32 {
+    let service = format!("test-{}", &Uuid::new_v4().to_simple().to_string()[..16]);
+    db.add_service(params::AddService {
+        service,
+        pattern: "{node}/1.5/{uid}".to_owned(),
+    })
+    .await
+    .unwrap()
+}
+
+async fn add_node(db: &dyn Db, service_id: i32, node: &str, current_load: i32) -> i64 {
+    db.add_node(params::AddNode {
+        service_id,
+        node: node.to_owned(),
+        capacity: 100,
+        available: 100 - current_load,
+        current_load,
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+}
+
+async fn allocate_user(
+    db: &dyn Db,
+    service_id: i32,
+    email: &str,
+    client_state: &str,
+    created_at: i64,
+) -> i64 {
+    db.allocate_user(params::AllocateUser {
+        service_id,
+        email: email.to_owned(),
+        generation: 1,
+        client_state: client_state.to_owned(),
+        keys_changed_at: Some(1),
+        created_at,
+    })
+    .await
+    .unwrap()
+    .uid
+}
+
+async fn get_user(db: &dyn Db, service_id: i32, email: &str) -> super::results::GetUser {
+    db.get_user(params::GetUser {
+        service_id,
+        email: email.to_owned(),
+    })
+    .await
+    .unwrap()
+    .unwrap()
+}
+
+async fn test_get_service_id(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    let other_id = add_service(db).await;
+    assert_ne!(service_id, other_id);
+
+    let service = format!("test-{}", &Uuid::new_v4().to_simple().to_string()[..16]);
+    let result = db.get_service_id(params::GetServiceId { service }).await;
+    assert!(result.is_err());
+}
+
+async fn test_allocate_user(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    let node_id = add_node(db, service_id, "https://node1", 0).await;
+    assert_eq!(
+        db.get_node_id(params::GetNodeId {
+            service_id,
+            node: "https://node1".to_owned(),
+        })
+        .await
+        .unwrap(),
+        node_id
+    );
+
+    let unknown = db
+        .get_user(params::GetUser {
+            service_id,
+            email: "test@example.com".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert!(unknown.is_none());
+
+    let uid = allocate_user(db, service_id, "test@example.com", "aaaa", 1000).await;
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, uid);
+    assert_eq!(user.node, Some("https://node1".to_owned()));
+    assert_eq!(user.generation, 1);
+    assert_eq!(user.keys_changed_at, Some(1));
+    assert_eq!(user.client_state, "aaaa");
+    assert!(user.old_client_states.is_empty());
+    assert_eq!(user.created_at, 1000);
+    assert_eq!(user.first_seen_at, 1000);
+    assert_eq!(user.replaced_at, None);
+}
+
+async fn test_insert_user(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    let node_id = add_node(db, service_id, "https://node1", 0).await;
+    let old_uid = allocate_user(db, service_id, "test@example.com", "aaaa", 1000).await;
+    let new_uid = db
+        .insert_user(params::InsertUser {
+            service_id,
+            email: "test@example.com".to_owned(),
+            generation: 2,
+            client_state: "bbbb".to_owned(),
+            keys_changed_at: Some(2),
+            created_at: 2000,
+            node_id,
+        })
+        .await
+        .unwrap();
+    assert_ne!(old_uid, new_uid);
+
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, new_uid);
+    assert_eq!(user.node, Some("https://node1".to_owned()));
+    assert_eq!(user.client_state, "bbbb");
+    assert_eq!(user.old_client_states, vec!["aaaa".to_owned()]);
+}
+
+async fn test_update_user(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    add_node(db, service_id, "https://node1", 0).await;
+    allocate_user(db, service_id, "test@example.com", "aaaa", 1000).await;
+
+    let update = |generation, keys_changed_at| params::UpdateUser {
+        service_id,
+        email: "test@example.com".to_owned(),
+        generation,
+        keys_changed_at,
+    };
+    db.update_user(update(Some(5), None)).await.unwrap();
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!((user.generation, user.keys_changed_at), (5, Some(1)));
+
+    db.update_user(update(None, Some(4))).await.unwrap();
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!((user.generation, user.keys_changed_at), (5, Some(4)));
+
+    // Going backwards is ignored.
+    db.update_user(update(Some(2), Some(2))).await.unwrap();
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!((user.generation, user.keys_changed_at), (5, Some(4)));
+}
+
+async fn test_replace_users(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    add_node(db, service_id, "https://node1", 0).await;
+    let old_uid = allocate_user(db, service_id, "test@example.com", "aaaa", 1000).await;
+    let new_uid = allocate_user(db, service_id, "test@example.com", "bbbb", 2000).await;
+    assert_ne!(old_uid, new_uid);
+
+    db.replace_users(params::ReplaceUsers {
+        service_id,
+        email: "test@example.com".to_owned(),
+        replaced_at: 2000,
+    })
+    .await
+    .unwrap();
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, new_uid);
+    assert_eq!(user.replaced_at, None);
+    assert_eq!(user.old_client_states, vec!["aaaa".to_owned()]);
+    assert_eq!(user.first_seen_at, 1000);
+
+    db.replace_user(params::ReplaceUser {
+        service_id,
+        uid: new_uid,
+        replaced_at: 3000,
+    })
+    .await
+    .unwrap();
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, new_uid);
+    assert_eq!(user.replaced_at, Some(3000));
+}
+
+async fn test_replace_user_record(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    add_node(db, service_id, "https://node1", 0).await;
+    add_node(db, service_id, "https://node2", 50).await;
+    let first_uid = allocate_user(db, service_id, "test@example.com", "aaaa", 1000).await;
+    // As if two requests had raced to create the user.
+    allocate_user(db, service_id, "test@example.com", "aaaa", 1000).await;
+
+    let replace = |client_state: &str, created_at, node: Option<&str>| {
+        db.replace_user_record(params::ReplaceUserRecord {
+            service_id,
+            email: "test@example.com".to_owned(),
+            generation: 2,
+            client_state: client_state.to_owned(),
+            keys_changed_at: Some(2),
+            created_at,
+            node: node.map(str::to_owned),
+        })
+    };
+    let record = replace("bbbb", 2000, Some("https://node2")).await.unwrap();
+    assert_ne!(record.uid, first_uid);
+    assert_eq!(record.node, "https://node2");
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, record.uid);
+    assert_eq!(user.replaced_at, None);
+    assert_eq!(user.client_state, "bbbb");
+    assert_eq!(user.old_client_states, vec!["aaaa".to_owned()]);
+
+    // Without a node, the new record goes on the least loaded one.
+    let record = replace("cccc", 3000, None).await.unwrap();
+    assert_eq!(record.node, "https://node1");
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, record.uid);
+    assert_eq!(user.first_seen_at, 1000);
+
+    // Marking the current record replaced leaves no record current, so
+    // none of the older ones were left live.
+    db.replace_user(params::ReplaceUser {
+        service_id,
+        uid: record.uid,
+        replaced_at: 4000,
+    })
+    .await
+    .unwrap();
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, record.uid);
+    assert_eq!(user.replaced_at, Some(4000));
+
+    assert!(replace("dddd", 5000, Some("https://unknown"))
+        .await
+        .is_err());
+}
+
+async fn test_retire_stale_records(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    add_node(db, service_id, "https://node1", 0).await;
+    // As if three requests had raced to create the user.
+    let first_uid = allocate_user(db, service_id, "test@example.com", "aaaa", 1000).await;
+    let second_uid = allocate_user(db, service_id, "test@example.com", "aaaa", 2000).await;
+    let third_uid = allocate_user(db, service_id, "test@example.com", "aaaa", 3000).await;
+    let get_records = || {
+        db.get_user_records(params::GetUser {
+            service_id,
+            email: "test@example.com".to_owned(),
+        })
+    };
+    assert_eq!(
+        get_records().await.unwrap(),
+        vec![(third_uid, None), (second_uid, None), (first_uid, None)]
+    );
+
+    // Reading the user retires the older records, each as of when the one
+    // after it was created.
+    let user = get_user(db, service_id, "test@example.com").await;
+    assert_eq!(user.uid, third_uid);
+    assert_eq!(user.first_seen_at, 1000);
+    assert_eq!(
+        get_records().await.unwrap(),
+        vec![
+            (third_uid, None),
+            (second_uid, Some(3000)),
+            (first_uid, Some(2000)),
+        ]
+    );
+}
+
+async fn test_get_best_node(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    add_node(db, service_id, "https://busy", 50).await;
+    add_node(db, service_id, "https://idle", 10).await;
+    db.add_node(params::AddNode {
+        service_id,
+        node: "https://downed".to_owned(),
+        capacity: 100,
+        available: 100,
+        downed: 1,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let node = db
+        .get_best_node(params::GetBestNode { service_id })
+        .await
+        .unwrap();
+    assert_eq!(node.node, "https://idle");
+
+    // Load is relative to capacity: 10/100 is less loaded than 2/10.
+    let service_id = add_service(db).await;
+    add_node(db, service_id, "https://big", 10).await;
+    db.add_node(params::AddNode {
+        service_id,
+        node: "https://small".to_owned(),
+        capacity: 10,
+        available: 8,
+        current_load: 2,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let node = db
+        .get_best_node(params::GetBestNode { service_id })
+        .await
+        .unwrap();
+    assert_eq!(node.node, "https://big");
+}
+
+async fn test_get_node_capacity(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    let capacity = |service_id| db.get_node_capacity(params::GetNodeCapacity { service_id });
+    assert_eq!(
+        capacity(service_id).await.unwrap(),
+        results::GetNodeCapacity::default()
+    );
+
+    add_node(db, service_id, "https://node1", 50).await;
+    add_node(db, service_id, "https://node2", 10).await;
+    db.add_node(params::AddNode {
+        service_id,
+        node: "https://downed".to_owned(),
+        capacity: 100,
+        available: 100,
+        downed: 1,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        capacity(service_id).await.unwrap(),
+        results::GetNodeCapacity {
+            capacity: 200,
+            current_load: 60,
+            available: 140,
+        }
+    );
+}
+
+async fn test_no_node_available(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    let result = db.get_best_node(params::GetBestNode { service_id }).await;
+    match result {
+        Err(e) => assert!(matches!(e.kind(), ApiErrorKind::NoNodeAvailable)),
+        Ok(node) => panic!("Unexpected node: {:?}", node),
+    }
+}
+
+async fn test_release_capacity(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    let capacity = |service_id| db.get_node_capacity(params::GetNodeCapacity { service_id });
+    db.add_node(params::AddNode {
+        service_id,
+        node: "https://node1".to_owned(),
+        capacity: 20,
+        available: 0,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    // The node has run out, so a tenth of its capacity is released for this
+    // allocation and the next one.
+    db.get_best_node(params::GetBestNode { service_id })
+        .await
+        .unwrap();
+    assert_eq!(
+        capacity(service_id).await.unwrap(),
+        results::GetNodeCapacity {
+            capacity: 20,
+            current_load: 1,
+            available: 1,
+        }
+    );
+
+    // It keeps coming back until the node is full.
+    for _ in 1..20 {
+        db.get_best_node(params::GetBestNode { service_id })
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        capacity(service_id).await.unwrap(),
+        results::GetNodeCapacity {
+            capacity: 20,
+            current_load: 20,
+            available: 0,
+        }
+    );
+    let result = db.get_best_node(params::GetBestNode { service_id }).await;
+    assert!(matches!(
+        result.unwrap_err().kind(),
+        ApiErrorKind::NoNodeAvailable
+    ));
+}
+
+async fn test_concurrent_allocation(db: &dyn Db) {
+    let service_id = add_service(db).await;
+    db.add_node(params::AddNode {
+        service_id,
+        node: "https://node1".to_owned(),
+        capacity: 10,
+        available: 10,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let results = join_all((0..20).map(|i| {
+        db.allocate_user(params::AllocateUser {
+            service_id,
+            email: format!("test{}@example.com", i),
+            client_state: "aaaa".to_owned(),
+            created_at: 1000,
+            ..Default::default()
+        })
+    }))
+    .await;
+    let allocated = results.iter().filter(|result| result.is_ok()).count();
+    assert_eq!(allocated, 10);
+}
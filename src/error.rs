@@ -17,10 +17,12 @@ use actix_web::{
 };
 use failure::{Backtrace, Context, Fail};
 use serde::{
-    ser::{SerializeMap, SerializeSeq, Serializer},
+    ser::{SerializeMap, Serializer},
     Serialize,
 };
 
+use crate::oauth::VerifyError;
+
 /// Common `Result` type.
 pub type ApiResult<T> = Result<T, ApiError>;
 
@@ -42,6 +44,49 @@ pub enum ApiErrorKind {
 
     #[fail(display = "{}", _0)]
     Internal(String),
+
+    #[fail(display = "Database error: {}", _0)]
+    Db(#[cause] diesel::result::Error),
+
+    #[fail(display = "Database pool error: {}", _0)]
+    DbPool(#[cause] diesel::r2d2::PoolError),
+
+    #[fail(display = "Could not connect to the database: {}", _0)]
+    DbConnection(#[cause] diesel::ConnectionError),
+
+    #[fail(display = "Database migration error: {}", _0)]
+    DbMigration(#[cause] diesel_migrations::RunMigrationsError),
+
+    #[fail(display = "Unable to get a node")]
+    NoNodeAvailable,
+
+    #[fail(display = "Unacceptable client-state value {}", reason)]
+    InvalidClientState {
+        reason: String,
+        /// The client state the user's current record requires.
+        new_users_required_state: Option<String>,
+    },
+
+    #[fail(display = "Unacceptable generation number")]
+    InvalidGeneration,
+
+    #[fail(display = "Unacceptable keys_changed_at value")]
+    InvalidKeysChangedAt,
+
+    #[fail(display = "Unauthorized")]
+    InvalidCredentials,
+
+    #[fail(display = "Unacceptable X-KeyID value {}", _0)]
+    InvalidKeyId(String),
+
+    #[fail(display = "User creation disabled")]
+    NewUsersDisabled,
+
+    #[fail(display = "Unsupported application")]
+    UnsupportedApplication,
+
+    #[fail(display = "{}", _0)]
+    Verify(#[cause] VerifyError),
 }
 
 impl ApiError {
@@ -49,7 +94,19 @@ impl ApiError {
         self.inner.get_context()
     }
 
+    /// Whether the error is worth reporting to Sentry. Client errors are
+    /// expected, so only server errors are.
+    pub fn is_reportable(&self) -> bool {
+        self.status.is_server_error()
+    }
+
     pub fn render_404<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
+        // Our own errors already have the body clients expect.
+        if let Some(error) = res.response().error() {
+            if error.as_error::<ApiError>().is_some() {
+                return Ok(ErrorHandlerResponse::Response(res));
+            }
+        }
         // Replace the outbound error message with our own.
         let resp = HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
         Ok(ErrorHandlerResponse::Response(ServiceResponse::new(
@@ -90,49 +147,109 @@ impl From<std::io::Error> for ApiError {
 
 impl From<Context<ApiErrorKind>> for ApiError {
     fn from(inner: Context<ApiErrorKind>) -> Self {
-        let status = match inner.get_context() {
-            ApiErrorKind::NoServerState | ApiErrorKind::Internal(_) => {
-                StatusCode::INTERNAL_SERVER_ERROR
+        let status = inner.get_context().details().0;
+        Self { inner, status }
+    }
+}
+
+impl ApiErrorKind {
+    /// The HTTP status of the error, along with the `status` and the
+    /// offending `location` and `name` the Python tokenserver reports for it.
+    fn details(&self) -> (StatusCode, &'static str, &'static str, &'static str) {
+        match self {
+            ApiErrorKind::Verify(VerifyError::RemoteUnavailable(_)) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "error", "internal", "")
             }
-        };
+            ApiErrorKind::InvalidCredentials | ApiErrorKind::Verify(_) => {
+                (StatusCode::UNAUTHORIZED, "invalid-credentials", "body", "")
+            }
+            ApiErrorKind::InvalidClientState { .. } => (
+                StatusCode::UNAUTHORIZED,
+                "invalid-client-state",
+                "header",
+                "X-Client-State",
+            ),
+            ApiErrorKind::InvalidGeneration => {
+                (StatusCode::UNAUTHORIZED, "invalid-generation", "body", "")
+            }
+            ApiErrorKind::InvalidKeysChangedAt => (
+                StatusCode::UNAUTHORIZED,
+                "invalid-keysChangedAt",
+                "body",
+                "",
+            ),
+            ApiErrorKind::InvalidKeyId(_) => (
+                StatusCode::UNAUTHORIZED,
+                "invalid-keyID",
+                "header",
+                "X-KeyID",
+            ),
+            ApiErrorKind::NewUsersDisabled => {
+                (StatusCode::UNAUTHORIZED, "new-users-disabled", "body", "")
+            }
+            ApiErrorKind::UnsupportedApplication => (
+                StatusCode::NOT_FOUND,
+                "unsupported-application",
+                "url",
+                "application",
+            ),
+            ApiErrorKind::DbPool(_)
+            | ApiErrorKind::DbConnection(_)
+            | ApiErrorKind::NoNodeAvailable => {
+                (StatusCode::SERVICE_UNAVAILABLE, "error", "internal", "")
+            }
+            ApiErrorKind::NoServerState
+            | ApiErrorKind::Internal(_)
+            | ApiErrorKind::Db(_)
+            | ApiErrorKind::DbMigration(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "error", "internal", "")
+            }
+        }
+    }
 
-        Self { inner, status }
+    /// The name errors of this kind are counted under.
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            ApiErrorKind::NoServerState => "no_server_state",
+            ApiErrorKind::Internal(_) => "internal",
+            ApiErrorKind::Db(_) => "db",
+            ApiErrorKind::DbPool(_) => "db_pool",
+            ApiErrorKind::DbConnection(_) => "db_connection",
+            ApiErrorKind::DbMigration(_) => "db_migration",
+            ApiErrorKind::NoNodeAvailable => "no_node_available",
+            ApiErrorKind::InvalidClientState { .. } => "invalid_client_state",
+            ApiErrorKind::InvalidGeneration => "invalid_generation",
+            ApiErrorKind::InvalidKeysChangedAt => "invalid_keys_changed_at",
+            ApiErrorKind::InvalidCredentials => "invalid_credentials",
+            ApiErrorKind::InvalidKeyId(_) => "invalid_key_id",
+            ApiErrorKind::NewUsersDisabled => "new_users_disabled",
+            ApiErrorKind::UnsupportedApplication => "unsupported_application",
+            ApiErrorKind::Verify(VerifyError::RemoteUnavailable(_)) => "verifier_unavailable",
+            ApiErrorKind::Verify(_) => "invalid_credentials",
+        }
     }
 }
 
 impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
-        // To return a descriptive error response, this would work. We do not
-        // unfortunately do that so that we can retain Sync 1.1 backwards compatibility
-        // as the Python one does.
-        // HttpResponse::build(self.status).json(self)
-        //
-        // So instead we translate our error to a backwards compatible one
-        HttpResponse::build(self.status)
-            .header("Retry-After", RETRY_AFTER.to_string())
-            .finish()
+        let mut response = HttpResponse::build(self.status);
+        if self.status == StatusCode::SERVICE_UNAVAILABLE {
+            response.header("Retry-After", RETRY_AFTER.to_string());
+        }
+        response.json(self)
     }
 }
 
+/// Serializes to the Python tokenserver's error body:
+/// `{"status": ..., "errors": [{"location": ..., "name": ..., "description": ...}]}`
 impl Serialize for ApiError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let size = if self.status == StatusCode::UNAUTHORIZED {
-            2
-        } else {
-            3
-        };
-
-        let mut map = serializer.serialize_map(Some(size))?;
-        map.serialize_entry("status", &self.status.as_u16())?;
-        map.serialize_entry("reason", self.status.canonical_reason().unwrap_or(""))?;
-
-        if self.status != StatusCode::UNAUTHORIZED {
-            map.serialize_entry("errors", &self.inner.get_context())?;
-        }
-
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("status", self.kind().details().1)?;
+        map.serialize_entry("errors", &[self.kind()])?;
         map.end()
     }
 }
@@ -142,27 +259,30 @@ impl Serialize for ApiErrorKind {
     where
         S: Serializer,
     {
-        match *self {
-            ApiErrorKind::Internal(ref description) => {
-                serialize_string_to_array(serializer, description)
-            }
-            ApiErrorKind::NoServerState => {
-                Serialize::serialize("No State information found", serializer)
-            }
+        let (status, _, location, name) = self.details();
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("location", location)?;
+        map.serialize_entry("name", name)?;
+        // Don't leak the details of server errors, or of why credentials
+        // were rejected, to clients.
+        if status.is_server_error() {
+            map.serialize_entry("description", status.canonical_reason().unwrap_or(""))?;
+        } else if let ApiErrorKind::Verify(_) = self {
+            map.serialize_entry("description", &ApiErrorKind::InvalidCredentials.to_string())?;
+        } else {
+            map.serialize_entry("description", &self.to_string())?;
         }
+        if let ApiErrorKind::InvalidClientState {
+            new_users_required_state: Some(state),
+            ..
+        } = self
+        {
+            map.serialize_entry("new_users_required_state", state)?;
+        }
+        map.end()
     }
 }
 
-fn serialize_string_to_array<S, V>(serializer: S, value: V) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-    V: fmt::Display,
-{
-    let mut seq = serializer.serialize_seq(Some(1))?;
-    seq.serialize_element(&value.to_string())?;
-    seq.end()
-}
-
 macro_rules! failure_boilerplate {
     ($error:ty, $kind:ty) => {
         impl Fail for $error {
@@ -191,7 +311,6 @@ macro_rules! failure_boilerplate {
 
 failure_boilerplate!(ApiError, ApiErrorKind);
 
-/*
 macro_rules! from_error {
     ($from:ty, $to:ty, $to_kind:expr) => {
         impl From<$from> for $to {
@@ -201,4 +320,17 @@ macro_rules! from_error {
         }
     };
 }
-*/
+
+from_error!(diesel::result::Error, ApiError, ApiErrorKind::Db);
+from_error!(diesel::r2d2::PoolError, ApiError, ApiErrorKind::DbPool);
+from_error!(
+    diesel::ConnectionError,
+    ApiError,
+    ApiErrorKind::DbConnection
+);
+from_error!(
+    diesel_migrations::RunMigrationsError,
+    ApiError,
+    ApiErrorKind::DbMigration
+);
+from_error!(VerifyError, ApiError, ApiErrorKind::Verify);
@@ -0,0 +1,231 @@
+//! The FxA public keys used to verify OAuth access tokens.
+//!
+//! Keys either come straight from the `jwks` setting or are fetched from the
+//! FxA auth server's `{auth_endpoint}/v1/jwks`. Fetched keys are cached and
+//! refreshed in the background once they are older than `jwks_cache_ttl`.
+//! A failed fetch leaves the last good set in place.
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::oauth::{KeySet, JWK};
+use crate::settings::Settings;
+
+/// How long to wait before retrying a failed fetch.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a fetch may take before it's abandoned.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum JwksError {
+    #[error("Could not fetch JWKS: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Invalid JWKS: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
+#[derive(Debug)]
+struct Cache {
+    keys: Arc<KeySet>,
+    /// When the keys were last fetched, if they ever were.
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Clone, Debug)]
+pub struct JwksProvider {
+    cache: Arc<RwLock<Cache>>,
+    /// Where to fetch the keys from. Configured keys are never refreshed.
+    url: Option<String>,
+    client: reqwest::Client,
+    ttl: Duration,
+    created_at: Instant,
+}
+
+impl JwksProvider {
+    /// Always use the given keys.
+    pub fn with_keys(jwks: JWK) -> Self {
+        Self::new(jwks, None, Duration::from_secs(0))
+    }
+
+    /// Fetch keys from `url`, keeping them for `ttl`. There are no keys until
+    /// the first successful `refresh`.
+    pub fn remote(url: &str, ttl: Duration) -> Self {
+        Self::new(JWK { keys: vec![] }, Some(url.to_owned()), ttl)
+    }
+
+    /// Use the configured `jwks` if set, otherwise fetch them from the
+    /// `auth_endpoint`.
+    pub fn from_settings(settings: &Settings) -> Result<Self, JwksError> {
+        if let Some(ref jwks) = settings.jwks {
+            return Ok(Self::with_keys(serde_json::from_str(jwks)?));
+        }
+        Ok(match settings.auth_endpoint {
+            Some(ref endpoint) => Self::remote(
+                &format!("{}/v1/jwks", endpoint.trim_end_matches('/')),
+                Duration::from_secs(settings.jwks_cache_ttl),
+            ),
+            None => Self::with_keys(JWK { keys: vec![] }),
+        })
+    }
+
+    fn new(jwks: JWK, url: Option<String>, ttl: Duration) -> Self {
+        Self {
+            cache: Arc::new(RwLock::new(Cache {
+                keys: Arc::new(KeySet::from(&jwks)),
+                fetched_at: None,
+            })),
+            url,
+            client: reqwest::Client::new(),
+            ttl,
+            created_at: Instant::now(),
+        }
+    }
+
+    /// The current keys.
+    pub fn keys(&self) -> Arc<KeySet> {
+        match self.cache.read() {
+            Ok(cache) => cache.keys.clone(),
+            Err(poisoned) => poisoned.into_inner().keys.clone(),
+        }
+    }
+
+    /// Whether the keys are due to be fetched again.
+    pub fn is_stale(&self) -> bool {
+        if self.url.is_none() {
+            return false;
+        }
+        let cache = match self.cache.read() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match cache.fetched_at {
+            Some(fetched_at) => fetched_at.elapsed() >= self.ttl,
+            None => true,
+        }
+    }
+
+    /// How long ago the keys were fetched, or for how long they've been
+    /// missing if they never were. Configured keys have no age.
+    pub fn age(&self) -> Option<Duration> {
+        self.url.as_ref()?;
+        let cache = match self.cache.read() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        Some(cache.fetched_at.unwrap_or(self.created_at).elapsed())
+    }
+
+    /// Fetch the keys again. On failure the current keys are kept.
+    pub async fn refresh(&self) -> Result<(), JwksError> {
+        let url = match self.url {
+            Some(ref url) => url,
+            None => return Ok(()),
+        };
+        let body = self
+            .client
+            .get(url)
+            .timeout(FETCH_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let jwks: JWK = serde_json::from_slice(&body)?;
+        let keys = KeySet::from(&jwks);
+
+        let mut cache = match self.cache.write() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        cache.keys = Arc::new(keys);
+        cache.fetched_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Keep the keys fresh from a background task on the current arbiter.
+    pub fn spawn_refresh(&self) {
+        if self.url.is_none() {
+            return;
+        }
+        let provider = self.clone();
+        actix_rt::spawn(async move {
+            loop {
+                let wait = match provider.refresh().await {
+                    Ok(()) => provider.ttl,
+                    Err(e) => {
+                        warn!("Keeping the current JWKS: {}", e);
+                        RETRY_INTERVAL.min(provider.ttl)
+                    }
+                };
+                actix_rt::time::delay_for(wait).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+
+    const JWKS: &str = r#"{"keys": [{"kty": "RSA", "n": "nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA", "e": "AQAB", "kid": "20190730-15e473fd"}]}"#;
+
+    #[actix_rt::test]
+    async fn test_remote() {
+        let url = format!("{}/v1/jwks", mockito::server_url());
+        let provider = JwksProvider::remote(&url, Duration::from_secs(3600));
+        assert!(provider.keys().is_empty());
+        assert!(provider.is_stale());
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        let missing_for = provider.age().unwrap();
+
+        let fetch = mock("GET", "/v1/jwks")
+            .with_header("content-type", "application/json")
+            .with_body(JWKS)
+            .create();
+        provider.refresh().await.unwrap();
+        fetch.assert();
+        assert_eq!(provider.keys().len(), 1);
+        assert!(!provider.is_stale());
+        assert!(provider.age().unwrap() < missing_for);
+
+        // Failed fetches keep the last good keys.
+        let fetch = mock("GET", "/v1/jwks").with_status(503).create();
+        assert!(provider.refresh().await.is_err());
+        fetch.assert();
+        assert_eq!(provider.keys().len(), 1);
+
+        let fetch = mock("GET", "/v1/jwks").with_body("{\"keys\":").create();
+        assert!(provider.refresh().await.is_err());
+        fetch.assert();
+        assert_eq!(provider.keys().len(), 1);
+    }
+
+    #[test]
+    fn test_from_settings() {
+        let provider = JwksProvider::from_settings(&Settings {
+            jwks: Some(JWKS.to_owned()),
+            auth_endpoint: Some("https://example.com".to_owned()),
+            ..Settings::default()
+        })
+        .unwrap();
+        assert_eq!(provider.keys().len(), 1);
+        assert!(!provider.is_stale());
+        assert_eq!(provider.age(), None);
+
+        let provider = JwksProvider::from_settings(&Settings {
+            auth_endpoint: Some("https://example.com/".to_owned()),
+            ..Settings::default()
+        })
+        .unwrap();
+        assert_eq!(provider.url.as_deref(), Some("https://example.com/v1/jwks"));
+
+        assert!(JwksProvider::from_settings(&Settings {
+            jwks: Some("{}".to_owned()),
+            ..Settings::default()
+        })
+        .is_err());
+    }
+}
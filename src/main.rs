@@ -1,20 +1,27 @@
 #![warn(rust_2018_idioms)]
 #![allow(clippy::try_err)]
 
-// extern crate diesel;
-// extern crate diesel_migrations;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 #[macro_use]
 extern crate slog_scope;
 
 #[macro_use]
 pub mod error;
+pub mod browserid;
+pub mod db;
+pub mod jwks;
 pub mod logging;
 pub mod metrics;
 pub mod oauth;
+pub mod secrets;
 pub mod server;
 pub mod settings;
 pub mod tags;
 pub mod token;
+pub mod tokenlib;
 
 use std::error::Error;
 
@@ -29,11 +36,14 @@ Usage: tokenserver [options]
 Options:
     -h, --help              Show this message
     --config=CONFIGFILE     Tokenserver Configuration file path
+    --check-config          Validate the configuration, print the effective
+                            settings (secrets masked) and exit
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_config: Option<String>,
+    flag_check_config: bool,
 }
 
 #[actix_rt::main]
@@ -42,7 +52,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
-    let settings = settings::Settings::with_env_and_config_file(&args.flag_config)?;
+    let settings = match settings::Settings::with_env_and_config_file(&args.flag_config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.flag_check_config {
+        println!("{}", serde_json::to_string_pretty(&settings.redacted())?);
+        return Ok(());
+    }
     init_logging(!settings.human_logs).expect("Logging failed to initialize");
     debug!("Starting up...");
 
@@ -62,7 +82,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // run server...
     println!("Hello, world!");
-    let server = server::Server::with_settings(settings).expect("Could not start server");
+    let server = match server::Server::with_settings(settings) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Could not start server: {}", e);
+            std::process::exit(1);
+        }
+    };
     server.await?;
 
     // shutdown
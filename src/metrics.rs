@@ -1,11 +1,20 @@
 use std::net::UdpSocket;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use actix_web::{error::ErrorInternalServerError, web::Data, Error, HttpRequest};
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::ErrorInternalServerError,
+    web::Data,
+    Error, FromRequest, HttpRequest,
+};
 use cadence::{
-    BufferedUdpMetricSink, Counted, Metric, NopMetricSink, QueuingMetricSink, StatsdClient, Timed,
+    BufferedUdpMetricSink, CountedExt, Distributed, Gauged, Histogrammed, Metric, MetricBuilder,
+    NopMetricSink, QueuingMetricSink, StatsdClient, Timed,
 };
+use futures::future::{self, Ready};
 
+use crate::db::{params, results, SYNC_SERVICE};
 use crate::error::ApiError;
 use crate::server::ServerState;
 use crate::settings::Settings;
@@ -20,7 +29,7 @@ pub struct MetricTimer {
 
 #[derive(Debug, Clone)]
 pub struct Metrics {
-    client: Option<StatsdClient>,
+    client: Option<Arc<StatsdClient>>,
     timer: Option<MetricTimer>,
     tags: Option<Tags>,
 }
@@ -57,25 +66,54 @@ impl Drop for Metrics {
 
 impl From<&HttpRequest> for Metrics {
     fn from(req: &HttpRequest) -> Self {
-        let exts = req.extensions();
-        let def_tags = Tags::from_request_head(req.head());
-        let tags = exts.get::<Tags>().unwrap_or_else(|| &def_tags);
+        let tags = Tags::from(req);
         Metrics {
             client: match req.app_data::<Data<ServerState>>() {
-                Some(v) => Some(*v.metrics.clone()),
+                Some(v) => Some(v.metrics.clone()),
                 None => {
                     warn!("⚠️ metric error: No App State");
                     None
                 }
             },
-            tags: Some(tags.clone()),
+            tags: Some(tags),
+            timer: None,
+        }
+    }
+}
+
+/// For middleware, which only has the request before it's handled.
+impl From<&ServiceRequest> for Metrics {
+    fn from(sreq: &ServiceRequest) -> Self {
+        let tags = match sreq.head().extensions().get::<Tags>() {
+            Some(tags) => tags.clone(),
+            None => Tags::from_request_head(sreq.head()),
+        };
+        Metrics {
+            client: match sreq.app_data::<ServerState>() {
+                Some(v) => Some(v.metrics.clone()),
+                None => {
+                    warn!("⚠️ metric error: No App State");
+                    None
+                }
+            },
+            tags: Some(tags),
             timer: None,
         }
     }
 }
 
-impl From<&StatsdClient> for Metrics {
-    fn from(client: &StatsdClient) -> Self {
+impl FromRequest for Metrics {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        future::ok(Metrics::from(req))
+    }
+}
+
+impl From<&Arc<StatsdClient>> for Metrics {
+    fn from(client: &Arc<StatsdClient>) -> Self {
         Metrics {
             client: Some(client.clone()),
             tags: None,
@@ -87,7 +125,7 @@ impl From<&StatsdClient> for Metrics {
 impl From<&actix_web::web::Data<ServerState>> for Metrics {
     fn from(state: &actix_web::web::Data<ServerState>) -> Self {
         Metrics {
-            client: Some(*state.metrics.clone()),
+            client: Some(state.metrics.clone()),
             tags: None,
             timer: None,
         }
@@ -101,7 +139,7 @@ impl Metrics {
 
     pub fn noop() -> Self {
         Self {
-            client: Some(Self::sink()),
+            client: Some(Arc::new(Self::sink())),
             timer: None,
             tags: None,
         }
@@ -121,6 +159,13 @@ impl Metrics {
         });
     }
 
+    /// Tag the running timer, for tags only known once it has started.
+    pub fn tag_timer(&mut self, key: &str, value: &str) {
+        if let Some(timer) = self.timer.as_mut() {
+            timer.tags.tags.insert(key.to_owned(), value.to_owned());
+        }
+    }
+
     // increment a counter with no tags data.
     pub fn incr(self, label: &str) {
         self.incr_with_tags(label, None)
@@ -128,30 +173,78 @@ impl Metrics {
 
     pub fn incr_with_tags(self, label: &str, tags: Option<Tags>) {
         if let Some(client) = self.client.as_ref() {
-            let mut tagged = client.incr_with_tags(label);
-            let mut mtags = self.tags.clone().unwrap_or_default();
-            if let Some(t) = tags {
-                mtags.tags.extend(t.tags)
-            }
-            let tag_keys = mtags.tags.keys();
-            for key in tag_keys.clone() {
-                // REALLY wants a static here, or at least a well defined ref.
-                tagged = tagged.with_tag(&key, &mtags.tags.get(key).unwrap());
-            }
-            // Include any "hard coded" tags.
-            // incr = incr.with_tag("version", env!("CARGO_PKG_VERSION"));
-            match tagged.try_send() {
-                Err(e) => {
-                    // eat the metric, but log the error
-                    warn!("⚠️ Metric {} error: {:?} ", label, e; mtags);
-                }
-                Ok(v) => trace!("☑️ {:?}", v.as_metric_str()),
-            }
+            let mtags = self.merge_tags(tags);
+            send(client.incr_with_tags(label), label, &mtags);
         }
     }
+
+    /// Record a value whose spread matters, like a size or a count per request.
+    pub fn histogram(self, label: &str, value: u64) {
+        self.histogram_with_tags(label, value, None)
+    }
+
+    pub fn histogram_with_tags(self, label: &str, value: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mtags = self.merge_tags(tags);
+            send(client.histogram_with_tags(label, value), label, &mtags);
+        }
+    }
+
+    /// Record the current level of something, like connections in use.
+    pub fn gauge(self, label: &str, value: u64) {
+        self.gauge_with_tags(label, value, None)
+    }
+
+    pub fn gauge_with_tags(self, label: &str, value: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mtags = self.merge_tags(tags);
+            send(client.gauge_with_tags(label, value), label, &mtags);
+        }
+    }
+
+    /// Record a value to be aggregated across hosts, rather than per host
+    /// like a histogram.
+    pub fn distribution(self, label: &str, value: u64) {
+        self.distribution_with_tags(label, value, None)
+    }
+
+    pub fn distribution_with_tags(self, label: &str, value: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mtags = self.merge_tags(tags);
+            send(client.distribution_with_tags(label, value), label, &mtags);
+        }
+    }
+
+    /// The request's tags with `tags` on top.
+    fn merge_tags(&self, tags: Option<Tags>) -> Tags {
+        let mut mtags = self.tags.clone().unwrap_or_default();
+        if let Some(t) = tags {
+            mtags.tags.extend(t.tags)
+        }
+        mtags
+    }
 }
 
-pub fn metrics_from_req(req: &HttpRequest) -> Result<Box<StatsdClient>, Error> {
+/// Tag a metric and send it, logging rather than failing if it can't be.
+fn send<'m, T>(mut tagged: MetricBuilder<'m, '_, T>, label: &str, mtags: &'m Tags)
+where
+    T: Metric + From<String>,
+{
+    for (key, value) in mtags.tags.iter() {
+        tagged = tagged.with_tag(key, value);
+    }
+    // Include any "hard coded" tags.
+    // incr = incr.with_tag("version", env!("CARGO_PKG_VERSION"));
+    match tagged.try_send() {
+        Err(e) => {
+            // eat the metric, but log the error
+            warn!("⚠️ Metric {} error: {:?} ", label, e; mtags);
+        }
+        Ok(v) => trace!("☑️ {:?}", v.as_metric_str()),
+    }
+}
+
+pub fn metrics_from_req(req: &HttpRequest) -> Result<Arc<StatsdClient>, Error> {
     Ok(req
         .app_data::<Data<ServerState>>()
         .ok_or_else(|| ErrorInternalServerError("Could not get state"))
@@ -179,3 +272,116 @@ pub fn metrics_from_opts(opts: &Settings) -> Result<StatsdClient, ApiError> {
         })
         .build())
 }
+
+/// Send the gauges no request would, every `interval`, from a background
+/// task on the current arbiter: the DB pool's connections, how old the JWKS
+/// is, and the capacity left on the Sync nodes.
+pub fn spawn_gauges(state: ServerState, interval: Duration) {
+    actix_rt::spawn(async move {
+        loop {
+            send_gauges(&state).await;
+            actix_rt::time::delay_for(interval).await;
+        }
+    });
+}
+
+pub(crate) async fn send_gauges(state: &ServerState) {
+    let metrics = || Metrics::from(&state.metrics);
+
+    let pool = state.db_pool.state();
+    metrics().gauge(
+        "db.pool.connections.active",
+        u64::from(pool.connections.saturating_sub(pool.idle_connections)),
+    );
+    metrics().gauge("db.pool.connections.idle", u64::from(pool.idle_connections));
+
+    if let Some(age) = state.jwks.age() {
+        metrics().gauge("jwks.age", age.as_secs());
+    }
+
+    match node_capacity(state).await {
+        Ok(nodes) => {
+            let level = |value: i64| value.max(0) as u64;
+            metrics().gauge("nodes.capacity", level(nodes.capacity));
+            metrics().gauge("nodes.current_load", level(nodes.current_load));
+            metrics().gauge("nodes.available", level(nodes.available));
+        }
+        Err(e) => warn!("⚠️ Could not get the node capacity: {}", e),
+    }
+}
+
+async fn node_capacity(state: &ServerState) -> Result<results::GetNodeCapacity, ApiError> {
+    let db = state.db_pool.get()?;
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE.to_owned(),
+        })
+        .await?;
+    db.get_node_capacity(params::GetNodeCapacity { service_id })
+        .await
+}
+
+/// A sink keeping every metric sent to it, for tests to check.
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+pub struct SpyMetricSink {
+    pub metrics: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl SpyMetricSink {
+    pub fn client(&self) -> StatsdClient {
+        StatsdClient::from_sink("", self.clone())
+    }
+
+    /// The metrics sent so far, without their tags.
+    pub fn names(&self) -> Vec<String> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|metric| metric.split('|').next().unwrap_or_default().to_owned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl cadence::MetricSink for SpyMetricSink {
+    fn emit(&self, metric: &str) -> std::io::Result<usize> {
+        self.metrics.lock().unwrap().push(metric.to_owned());
+        Ok(metric.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merged_tags() {
+        let sink = SpyMetricSink::default();
+        let mut tags = Tags::default();
+        tags.tags.insert("uri.method".to_owned(), "GET".to_owned());
+        let metrics = || Metrics {
+            client: Some(Arc::new(sink.client())),
+            timer: None,
+            tags: Some(tags.clone()),
+        };
+        let mut extra = Tags::default();
+        extra.tags.insert("pool".to_owned(), "db".to_owned());
+
+        metrics().histogram_with_tags("size", 10, Some(extra.clone()));
+        metrics().gauge_with_tags("connections", 2, Some(extra));
+        metrics().distribution("latency", 5);
+
+        let sent = sink.metrics.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].starts_with("size:10|h|#"), "{}", sent[0]);
+        assert!(sent[1].starts_with("connections:2|g|#"), "{}", sent[1]);
+        assert_eq!(sent[2], "latency:5|d|#uri.method:GET");
+        for metric in &sent[..2] {
+            assert!(metric.contains("uri.method:GET"), "{}", metric);
+            assert!(metric.contains("pool:db"), "{}", metric);
+        }
+    }
+}
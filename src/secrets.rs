@@ -0,0 +1,197 @@
+//! Per-node secrets used to sign tokens.
+//!
+//! Secrets are read from a file in the Python tokenserver's `secrets_file`
+//! format, one node per line:
+//!
+//! ```text
+//! https://node1.example.com,1570000000:secret1,1580000000:secret2
+//! ```
+//!
+//! Each secret carries the timestamp it was created at. The newest secret
+//! signs new tokens while older ones still verify tokens issued before a
+//! rotation.
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::tokenlib::{self, TokenPayload, TokenlibError};
+
+#[derive(Error, Debug)]
+pub enum SecretsError {
+    #[error("Could not read secrets file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid secrets entry on line {0}")]
+    InvalidLine(usize),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Secrets {
+    /// Secrets for each node, oldest first
+    nodes: HashMap<String, Vec<(u64, String)>>,
+    /// Secret for nodes without their own entry
+    fallback: Option<String>,
+}
+
+impl Secrets {
+    /// Use a single secret for every node.
+    pub fn with_shared_secret(secret: &str) -> Self {
+        Secrets {
+            nodes: HashMap::new(),
+            fallback: Some(secret.to_owned()),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, SecretsError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, SecretsError> {
+        let mut nodes: HashMap<String, Vec<(u64, String)>> = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || SecretsError::InvalidLine(number + 1);
+
+            let mut fields = line.split(',');
+            let node = fields
+                .next()
+                .filter(|n| !n.is_empty())
+                .ok_or_else(invalid)?;
+            let secrets = nodes.entry(node.to_owned()).or_default();
+            for field in fields {
+                let mut parts = field.splitn(2, ':');
+                let timestamp = parts
+                    .next()
+                    .and_then(|t| t.trim().parse().ok())
+                    .ok_or_else(invalid)?;
+                let secret = parts
+                    .next()
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .ok_or_else(invalid)?;
+                secrets.push((timestamp, secret.to_owned()));
+            }
+            if secrets.is_empty() {
+                return Err(invalid());
+            }
+        }
+        for secrets in nodes.values_mut() {
+            secrets.sort_by_key(|(timestamp, _)| *timestamp);
+        }
+        Ok(Secrets {
+            nodes,
+            fallback: None,
+        })
+    }
+
+    /// All the valid secrets for `node`, newest first.
+    pub fn get(&self, node: &str) -> Vec<&str> {
+        match self.nodes.get(node) {
+            Some(secrets) => secrets.iter().rev().map(|(_, s)| s.as_str()).collect(),
+            None => self.fallback.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// The secret new tokens for `node` are signed with.
+    pub fn signing_secret(&self, node: &str) -> Option<&str> {
+        self.get(node).into_iter().next()
+    }
+
+    /// Check a token issued for `node` against each of its secrets.
+    pub fn parse_token(&self, node: &str, token: &str) -> Result<TokenPayload, TokenlibError> {
+        let mut result = Err(TokenlibError::InvalidSignature);
+        for secret in self.get(node) {
+            result = tokenlib::parse_token(token, secret);
+            match result {
+                Err(TokenlibError::InvalidSignature) => continue,
+                _ => break,
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRETS: &str = "
+https://node1.example.com,1580000000:newer,1570000000:older
+https://node2.example.com,1570000000:only
+";
+
+    fn payload(node: &str) -> TokenPayload {
+        TokenPayload {
+            uid: 1,
+            node: node.to_owned(),
+            expires: u64::MAX,
+            fxa_uid: "fxa_uid".to_owned(),
+            fxa_kid: "0000000000000-".to_owned(),
+            hashed_fxa_uid: "".to_owned(),
+            hashed_device_id: "".to_owned(),
+            salt: tokenlib::new_salt(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let secrets = Secrets::parse(SECRETS).unwrap();
+        assert_eq!(
+            secrets.get("https://node1.example.com"),
+            vec!["newer", "older"]
+        );
+        assert_eq!(
+            secrets.signing_secret("https://node2.example.com"),
+            Some("only")
+        );
+        assert_eq!(secrets.signing_secret("https://unknown.example.com"), None);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for contents in &[
+            "https://node1.example.com",
+            "https://node1.example.com,secret",
+            "https://node1.example.com,abc:secret",
+            "https://node1.example.com,1570000000:",
+            ",1570000000:secret",
+        ] {
+            assert!(
+                matches!(Secrets::parse(contents), Err(SecretsError::InvalidLine(1))),
+                "{} should be rejected",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let secrets = Secrets::with_shared_secret("shared");
+        assert_eq!(
+            secrets.signing_secret("https://any.example.com"),
+            Some("shared")
+        );
+    }
+
+    #[test]
+    fn test_rotated_secrets_verify() {
+        let node = "https://node1.example.com";
+        let secrets = Secrets::parse(SECRETS).unwrap();
+        let old_token = tokenlib::make_token(&payload(node), "older").unwrap();
+        let new_token = tokenlib::make_token(&payload(node), "newer").unwrap();
+        let bad_token = tokenlib::make_token(&payload(node), "bogus").unwrap();
+
+        assert!(secrets.parse_token(node, &old_token).is_ok());
+        assert!(secrets.parse_token(node, &new_token).is_ok());
+        assert!(matches!(
+            secrets.parse_token(node, &bad_token),
+            Err(TokenlibError::InvalidSignature)
+        ));
+        assert!(matches!(
+            secrets.parse_token("https://node2.example.com", &old_token),
+            Err(TokenlibError::InvalidSignature)
+        ));
+    }
+}
@@ -1,15 +1,153 @@
-use actix_web::error::ErrorBadRequest;
-use actix_web::{Error, FromRequest, Responder};
-use futures::future::{err, ok, Ready};
+use actix_web::web::Data;
+use actix_web::{Error, FromRequest, HttpRequest, Responder};
+use futures::future::{err, ready, LocalBoxFuture, Ready};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Deserialize;
+
+use super::ServerState;
+use crate::error::{ApiError, ApiErrorKind};
+use crate::metrics::Metrics;
+use crate::oauth::{self, VerifyError, SYNC_SCOPE};
+use crate::tags::Tags;
+
 lazy_static! {
     static ref RE_EXP: Regex = Regex::new(r"^[a-zA-Z0-9\._\-]{1,32}$").unwrap();
 }
+
+/// The verified identity of a client requesting a token.
+#[derive(Debug)]
+pub struct TokenserverRequest {
+    pub fxa_uid: String,
+    pub email: String,
+    pub client_state: String,
+    /// The client's view of the account's generation number, if known.
+    pub generation: Option<i64>,
+    /// When the client's view of the account's keys last changed, if known.
+    pub keys_changed_at: Option<i64>,
+    /// The client's device, if it says. Only BrowserID clients do.
+    pub device_id: Option<String>,
+}
+
+impl FromRequest for TokenserverRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let state = match req.app_data::<Data<ServerState>>() {
+            Some(state) => state.clone(),
+            None => return Box::pin(err(ApiError::from(ApiErrorKind::NoServerState).into())),
+        };
+        let application = req.match_info().get("application");
+        let version = req.match_info().get("version");
+        if (application, version) != (Some("sync"), Some("1.5")) {
+            return Box::pin(err(
+                ApiError::from(ApiErrorKind::UnsupportedApplication).into()
+            ));
+        }
+        let credentials = match Credentials::from_headers(req) {
+            Some(credentials) => credentials,
+            None => return Box::pin(err(ApiError::from(ApiErrorKind::InvalidCredentials).into())),
+        };
+        let tags = Tags::from(req);
+        let metrics = Metrics::from(req);
+
+        match credentials {
+            Credentials::Bearer(token) => {
+                let key_id = KeyId::from_headers(req);
+                Box::pin(async move {
+                    let response = oauth::verify(
+                        &token,
+                        &state.jwks.keys(),
+                        &state.claim_validation,
+                        state.remote_verifier.as_ref(),
+                        &Some(vec![SYNC_SCOPE.to_owned()]),
+                    )
+                    .await
+                    .map_err(|e| verify_failed(e, "oauth", tags, metrics))?;
+                    let key_id = key_id?;
+
+                    Ok(TokenserverRequest {
+                        fxa_uid: response.user,
+                        email: response.email,
+                        client_state: key_id.client_state,
+                        generation: response.generation,
+                        keys_changed_at: Some(key_id.keys_changed_at),
+                        device_id: None,
+                    })
+                })
+            }
+            Credentials::BrowserId(assertion) => {
+                let client_state = ClientState::from_headers(req);
+                Box::pin(async move {
+                    let verifier = match state.browserid_verifier {
+                        Some(ref verifier) => verifier,
+                        None => return Err(ApiError::from(ApiErrorKind::InvalidCredentials).into()),
+                    };
+                    let assertion = verifier
+                        .verify(&assertion)
+                        .await
+                        .map_err(|e| verify_failed(e, "browserid", tags, metrics))?;
+                    let client_state = client_state?;
+
+                    Ok(TokenserverRequest {
+                        fxa_uid: assertion.user,
+                        email: assertion.email,
+                        client_state: client_state.value,
+                        generation: assertion.generation,
+                        keys_changed_at: assertion.keys_changed_at,
+                        device_id: assertion.device_id,
+                    })
+                })
+            }
+        }
+    }
+}
+
+/// Log and count credentials that failed to verify, tagged with why.
+fn verify_failed(e: VerifyError, method: &str, mut tags: Tags, metrics: Metrics) -> ApiError {
+    tags.tags.insert("reason".to_owned(), e.reason().to_owned());
+    match e {
+        VerifyError::RemoteUnavailable(_) => {
+            warn!("Could not verify {} credentials: {}", method, e; tags.clone())
+        }
+        _ => info!("Rejected {} credentials: {}", method, e; tags.clone()),
+    }
+    metrics.incr_with_tags(&format!("token.{}.verify_failure", method), Some(tags));
+    ApiError::from(e)
+}
+
+/// The credentials in the `Authorization` header.
+#[derive(Debug)]
+enum Credentials {
+    /// An FxA OAuth access token.
+    Bearer(String),
+    /// A BrowserID assertion, from older clients.
+    BrowserId(String),
+}
+
+impl Credentials {
+    fn from_headers(req: &HttpRequest) -> Option<Self> {
+        let header = req.headers().get("Authorization")?.to_str().ok()?;
+        let mut parts = header.splitn(2, ' ');
+        let (scheme, value) = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(value)) => (scheme, value.trim().to_owned()),
+            _ => return None,
+        };
+        if scheme.eq_ignore_ascii_case("bearer") {
+            Some(Credentials::Bearer(value))
+        } else if scheme.eq_ignore_ascii_case("browserid") {
+            Some(Credentials::BrowserId(value))
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ClientState {
-    value: String,
+    pub value: String,
 }
 
 impl FromRequest for ClientState {
@@ -22,23 +160,93 @@ impl FromRequest for ClientState {
         req: &::actix_web::HttpRequest,
         _payload: &mut actix_web::dev::Payload,
     ) -> Self::Future {
-        let client_state: &str = req
-            .headers()
-            .get("X-Client-State")
-            .unwrap()
-            .to_str()
-            .unwrap();
+        ready(Self::from_headers(req))
+    }
+}
+
+impl ClientState {
+    fn from_headers(req: &HttpRequest) -> Result<Self, Error> {
+        let client_state = match req.headers().get("X-Client-State") {
+            Some(header) => header.to_str().unwrap_or_default(),
+            // Clients that have never set a client state send none.
+            None => {
+                return Ok(ClientState {
+                    value: String::new(),
+                })
+            }
+        };
 
         if RE_EXP.is_match(client_state) {
-            ok(ClientState {
+            Ok(ClientState {
                 value: client_state.to_string(),
             })
         } else {
-            err(ErrorBadRequest("Invalid Client State."))
+            Err(ApiError::from(ApiErrorKind::InvalidClientState {
+                reason: "malformed value".to_owned(),
+                new_users_required_state: None,
+            })
+            .into())
         }
     }
 }
 
+/// The `X-KeyID: <keys_changed_at>-<client state>` header OAuth clients
+/// send, with the client state as unpadded urlsafe-base64 rather than hex.
+#[derive(Debug)]
+pub struct KeyId {
+    pub keys_changed_at: i64,
+    /// The client state, hex encoded like `X-Client-State`.
+    pub client_state: String,
+}
+
+impl FromRequest for KeyId {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(Self::from_headers(req))
+    }
+}
+
+impl KeyId {
+    fn from_headers(req: &HttpRequest) -> Result<Self, Error> {
+        let header = match req.headers().get("X-KeyID") {
+            Some(header) => header,
+            None => return Err(ApiError::from(ApiErrorKind::InvalidCredentials).into()),
+        };
+        let key_id = Self::parse(header.to_str().unwrap_or_default()).ok_or_else(|| {
+            ApiError::from(ApiErrorKind::InvalidKeyId("malformed value".to_owned()))
+        })?;
+
+        // Clients may still send X-Client-State, but it has to agree.
+        let client_state = ClientState::from_headers(req)?;
+        if !client_state.value.is_empty() && client_state.value != key_id.client_state {
+            return Err(ApiError::from(ApiErrorKind::InvalidClientState {
+                reason: "mismatch with X-KeyID".to_owned(),
+                new_users_required_state: None,
+            })
+            .into());
+        }
+        Ok(key_id)
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(2, '-');
+        let keys_changed_at = parts.next()?.parse::<i64>().ok()?;
+        let key_hash =
+            base64::decode_config(parts.next()?.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                .ok()?;
+        if keys_changed_at < 0 || key_hash.is_empty() {
+            return None;
+        }
+        Some(KeyId {
+            keys_changed_at,
+            client_state: hex::encode(key_hash),
+        })
+    }
+}
+
 async fn extract_client_state(state: ClientState) -> impl Responder {
     state.value
 }
@@ -57,12 +265,7 @@ async fn test_extractor1() {
         .uri("/test/extractor")
         .to_request();
     let res = test::call_service(&mut app, req).await;
-
-    let var = test::read_body(res).await;
-    let var2 = std::str::from_utf8(&var).unwrap();
-    if !RE_EXP.is_match(var2) {
-        assert_eq!(var2, "Invalid Client State.");
-    }
+    assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
 }
 
 #[actix_rt::test]
@@ -81,12 +284,7 @@ async fn test_extractor2() {
     .uri("/test/extractor")
     .to_request();
     let res = test::call_service(&mut app, req).await;
-
-    let var = test::read_body(res).await;
-    let var2 = std::str::from_utf8(&var).unwrap();
-    if !RE_EXP.is_match(var2) {
-        assert_eq!(var2, "Invalid Client State.");
-    }
+    assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
 }
 
 #[actix_rt::test]
@@ -102,10 +300,18 @@ async fn test_extractor3() {
         .uri("/test/extractor")
         .to_request();
     let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn test_parse_key_id() {
+    let key_id = KeyId::parse("1234-qqo").unwrap();
+    assert_eq!(key_id.keys_changed_at, 1234);
+    assert_eq!(key_id.client_state, "aaaa");
+    let key_id = KeyId::parse("0000000001234-qqo=").unwrap();
+    assert_eq!(key_id.keys_changed_at, 1234);
 
-    let var = test::read_body(res).await;
-    let var2 = std::str::from_utf8(&var).unwrap();
-    if !RE_EXP.is_match(var2) {
-        assert_eq!(var2, "Invalid Client State.");
+    for value in &["", "1234", "1234-", "-qqo", "abc-qqo", "-1-qqo", "1234-q!o"] {
+        assert!(KeyId::parse(value).is_none(), "{} should be invalid", value);
     }
 }
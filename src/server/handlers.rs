@@ -1,21 +1,1010 @@
-use actix_web::{HttpRequest, HttpResponse, Responder};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub async fn get_handler(_req: HttpRequest) -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .body("{}")
+use actix_web::{web::Data, HttpResponse};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use super::extractors::TokenserverRequest;
+use super::ServerState;
+use crate::db::{params, Db, SYNC_SERVICE};
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::metrics::Metrics;
+use crate::tokenlib::{self, TokenPayload};
+
+/// The token returned to clients, matching the Python tokenserver's response.
+#[derive(Debug, Serialize)]
+pub struct TokenserverResult {
+    id: String,
+    key: String,
+    uid: i64,
+    api_endpoint: String,
+    duration: u64,
+    hashed_fxa_uid: String,
+    hashed_device_id: String,
+    node_type: String,
+}
+
+pub async fn get_handler(
+    req: TokenserverRequest,
+    state: Data<ServerState>,
+    metrics: Metrics,
+) -> ApiResult<HttpResponse> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| ApiErrorKind::Internal(e.to_string()))?;
+    let key_hash =
+        hex::decode(&req.client_state).map_err(|_| ApiErrorKind::InvalidClientState {
+            reason: "malformed value".to_owned(),
+            new_users_required_state: None,
+        })?;
+    let db = state.db_pool.get()?;
+    let user = assign_user(&*db, &req, state.allow_new_users, now.as_millis() as i64).await?;
+    match user.outcome {
+        Outcome::NewUser => metrics.clone().incr("token.new_user"),
+        Outcome::ReplacedUser => metrics.clone().incr("token.replaced_user"),
+        Outcome::Existing => (),
+    }
+    metrics.incr("token.success");
+
+    let hashed_fxa_uid = fxa_metrics_hash(&req.fxa_uid, &state.fxa_metrics_hash_secret);
+    // OAuth clients don't identify their device, so hash the same placeholder
+    // the Python tokenserver does.
+    let hashed_device_id = hash_device_id(
+        &req.fxa_uid,
+        req.device_id.as_deref().unwrap_or("none"),
+        &state.fxa_metrics_hash_secret,
+    );
+
+    let payload = TokenPayload {
+        uid: user.uid,
+        node: user.node.clone(),
+        expires: now.as_secs() + state.token_duration,
+        fxa_uid: req.fxa_uid,
+        fxa_kid: format_key_id(user.keys_changed_at.unwrap_or(user.generation), &key_hash),
+        hashed_fxa_uid: hashed_fxa_uid.clone(),
+        hashed_device_id: hashed_device_id.clone(),
+        salt: tokenlib::new_salt(),
+    };
+    let secret = state
+        .secrets
+        .signing_secret(&user.node)
+        .ok_or_else(|| ApiErrorKind::Internal(format!("No secret for node {}", user.node)))?;
+    let id = tokenlib::make_token(&payload, secret)
+        .map_err(|e| ApiErrorKind::Internal(e.to_string()))?;
+    let key = tokenlib::get_derived_secret(&id, secret)
+        .map_err(|e| ApiErrorKind::Internal(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(TokenserverResult {
+        id,
+        key,
+        uid: user.uid,
+        api_endpoint: format!("{}/1.5/{}", user.node, user.uid),
+        duration: state.token_duration,
+        hashed_fxa_uid,
+        hashed_device_id,
+        node_type: state.node_type.clone(),
+    }))
+}
+
+/// Dockerflow's `__heartbeat__`: the status of each thing the server needs
+/// to issue tokens, failing with a 503 if any of them is unhealthy.
+pub async fn heartbeat(state: Data<ServerState>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+    let mut healthy = true;
+    let mut record = |name: &'static str, result: Result<(), String>| {
+        let status = match result {
+            Ok(()) => "ok",
+            Err(e) => {
+                warn!("Heartbeat check failed"; "check" => name, "error" => e);
+                healthy = false;
+                "error"
+            }
+        };
+        checks.insert(name, status);
+    };
+
+    let database = match state.db_pool.get() {
+        Ok(db) => db.check().await,
+        Err(e) => Err(e),
+    };
+    record("database", database.map_err(|e| e.to_string()));
+    record(
+        "jwks",
+        if state.jwks.keys().is_empty() {
+            Err("no keys".to_owned())
+        } else {
+            Ok(())
+        },
+    );
+    if let Some(ref verifier) = state.remote_verifier {
+        record(
+            "oauth_verifier",
+            verifier.check().await.map_err(|e| e.to_string()),
+        );
+    }
+
+    let mut body = serde_json::json!({
+        "status": if healthy { "ok" } else { "error" },
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    for (name, status) in checks {
+        body[name] = status.into();
+    }
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Dockerflow's `__error__`: fail on purpose, to check errors are reported.
+pub async fn test_error() -> ApiResult<HttpResponse> {
+    error!("Test error");
+    Err(ApiErrorKind::Internal("Oh Noes!".to_owned()).into())
+}
+
+/// The user record a token is issued for.
+#[derive(Debug)]
+struct Assignment {
+    uid: i64,
+    node: String,
+    generation: i64,
+    keys_changed_at: Option<i64>,
+    outcome: Outcome,
+}
+
+/// How the user came by their record, for metrics.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// The user's current record.
+    Existing,
+    /// The user's first record.
+    NewUser,
+    /// A new record replacing the user's current one, for a new client
+    /// state or because the user was moved off their node.
+    ReplacedUser,
+}
+
+/// Find the user's current record, allocating one if they are new and
+/// replacing it if the client has moved on to a new client state, as the
+/// Python tokenserver does. A new record gets a new uid, so the storage node
+/// sees a new user and the client starts its data afresh.
+async fn assign_user(
+    db: &dyn Db,
+    req: &TokenserverRequest,
+    allow_new_users: bool,
+    now: i64,
+) -> ApiResult<Assignment> {
+    // Keys can only have changed along with the account's generation.
+    if let (Some(generation), Some(keys_changed_at)) = (req.generation, req.keys_changed_at) {
+        if keys_changed_at > generation {
+            return Err(ApiErrorKind::InvalidKeysChangedAt.into());
+        }
+    }
+
+    let client_state = req.client_state.as_str();
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE.to_owned(),
+        })
+        .await?;
+    let user = db
+        .get_user(params::GetUser {
+            service_id,
+            email: req.email.clone(),
+        })
+        .await?;
+    let user = match user {
+        Some(user) => user,
+        None if !allow_new_users => return Err(ApiErrorKind::NewUsersDisabled.into()),
+        None => {
+            let generation = req.generation.unwrap_or(0);
+            return allocate_user(
+                db,
+                service_id,
+                req,
+                generation,
+                req.keys_changed_at,
+                now,
+                Outcome::NewUser,
+            )
+            .await;
+        }
+    };
+
+    // Clients behind what another client has already seen are rejected.
+    if req.generation.is_some() && req.generation < Some(user.generation) {
+        return Err(ApiErrorKind::InvalidGeneration.into());
+    }
+    if req.keys_changed_at.is_some() && req.keys_changed_at < user.keys_changed_at {
+        return Err(ApiErrorKind::InvalidKeysChangedAt.into());
+    }
+    let generation_changed = req.generation > Some(user.generation);
+    let keys_changed = req.keys_changed_at > user.keys_changed_at;
+    let generation = user.generation.max(req.generation.unwrap_or(0));
+    let keys_changed_at = user.keys_changed_at.max(req.keys_changed_at);
+
+    if client_state != user.client_state {
+        let invalid = |reason: &str| ApiErrorKind::InvalidClientState {
+            reason: reason.to_owned(),
+            new_users_required_state: Some(user.client_state.clone()),
+        };
+        if client_state.is_empty() {
+            return Err(invalid("empty string").into());
+        }
+        if user.old_client_states.iter().any(|old| old == client_state) {
+            return Err(invalid("stale value").into());
+        }
+        // The keys can't have changed unless the account did.
+        if req.generation.is_some() && !generation_changed {
+            return Err(invalid("new value with no generation change").into());
+        }
+        if req.keys_changed_at.is_some() && !keys_changed {
+            return Err(invalid("new value with no keys_changed_at change").into());
+        }
+
+        // The new record stays on the user's node, unless they were moved
+        // off it.
+        let node = match (user.node, user.replaced_at) {
+            (Some(node), None) => Some(node),
+            _ => None,
+        };
+        let record = db
+            .replace_user_record(params::ReplaceUserRecord {
+                service_id,
+                email: req.email.clone(),
+                generation,
+                client_state: client_state.to_owned(),
+                keys_changed_at,
+                created_at: now,
+                node,
+            })
+            .await?;
+        return Ok(Assignment {
+            uid: record.uid,
+            node: record.node,
+            generation,
+            keys_changed_at,
+            outcome: Outcome::ReplacedUser,
+        });
+    }
+
+    let node = match (user.node, user.replaced_at) {
+        (Some(node), None) => node,
+        // The user was moved off their node: start afresh on another.
+        _ => {
+            return allocate_user(
+                db,
+                service_id,
+                req,
+                generation,
+                keys_changed_at,
+                now,
+                Outcome::ReplacedUser,
+            )
+            .await
+        }
+    };
+    if generation_changed || keys_changed {
+        db.update_user(params::UpdateUser {
+            service_id,
+            email: req.email.clone(),
+            generation: req.generation,
+            keys_changed_at: req.keys_changed_at,
+        })
+        .await?;
+    }
+    Ok(Assignment {
+        uid: user.uid,
+        node,
+        generation,
+        keys_changed_at,
+        outcome: Outcome::Existing,
+    })
+}
+
+async fn allocate_user(
+    db: &dyn Db,
+    service_id: i32,
+    req: &TokenserverRequest,
+    generation: i64,
+    keys_changed_at: Option<i64>,
+    now: i64,
+    outcome: Outcome,
+) -> ApiResult<Assignment> {
+    let user = db
+        .allocate_user(params::AllocateUser {
+            service_id,
+            email: req.email.clone(),
+            generation,
+            client_state: req.client_state.clone(),
+            keys_changed_at,
+            created_at: now,
+        })
+        .await?;
+    Ok(Assignment {
+        uid: user.uid,
+        node: user.node,
+        generation,
+        keys_changed_at,
+        outcome,
+    })
+}
+
+fn hmac_sha256(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC can take a key of any size");
+    mac.update(value);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Hash a value for metrics the way FxA does: HMAC-SHA256 of the local part
+/// of the value, truncated to 32 hex characters.
+fn fxa_metrics_hash(value: &str, secret: &str) -> String {
+    let local = value.split('@').next().unwrap_or(value);
+    let mut hash = hex::encode(hmac_sha256(secret.as_bytes(), local.as_bytes()));
+    hash.truncate(32);
+    hash
+}
+
+fn hash_device_id(fxa_uid: &str, device_id: &str, secret: &str) -> String {
+    fxa_metrics_hash(&format!("{}{}", fxa_uid, device_id), secret)
+}
+
+/// Format the `fxa_kid` the storage node uses to detect key changes:
+/// the zero-padded `keys_changed_at` and the unpadded urlsafe-base64 of the
+/// client state's bytes.
+fn format_key_id(keys_changed_at: i64, key_hash: &[u8]) -> String {
+    format!(
+        "{:013}-{}",
+        keys_changed_at,
+        base64::encode_config(key_hash, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+#[cfg(test)]
+fn test_state(jwks: crate::oauth::JWK) -> ServerState {
+    use crate::db::memory::MemoryDbPool;
+    use crate::settings::Settings;
+    use std::sync::Arc;
+
+    let settings = Settings {
+        service_entry: Some("https://example.com".to_owned()),
+        ..Settings::default()
+    };
+
+    ServerState {
+        metrics: Arc::new(Metrics::sink()),
+        port: 8000,
+        db_pool: Box::new(MemoryDbPool::new(&settings).unwrap()),
+        jwks: crate::jwks::JwksProvider::with_keys(jwks),
+        claim_validation: Default::default(),
+        remote_verifier: None,
+        signing_keys: None,
+        browserid_verifier: None,
+        node_type: "mysql".to_owned(),
+        token_duration: 3600,
+        secrets: Arc::new(crate::secrets::Secrets::with_shared_secret(
+            "Ted Koppel is a robot",
+        )),
+        fxa_metrics_hash_secret: "foo".to_owned(),
+        allow_new_users: true,
+    }
+}
+
+#[cfg(test)]
+async fn test_app(
+    state: ServerState,
+) -> impl actix_web::dev::Service<
+    Request = actix_http::Request,
+    Response = actix_web::dev::ServiceResponse,
+    Error = actix_web::Error,
+> {
+    use super::TOKEN_ROUTE;
+    use actix_web::{test, web, App};
+
+    test::init_service(
+        App::new()
+            .data(state)
+            .service(web::resource(TOKEN_ROUTE).route(web::get().to(get_handler))),
+    )
+    .await
 }
+
 #[actix_rt::test]
 async fn test_index() {
+    use actix_web::test;
+    let mut app = test_app(test_state(crate::oauth::JWK { keys: vec![] })).await;
+
+    let req = test::TestRequest::get().uri("/1.0/sync/1.5").to_request();
+    let res = test::call_service(&mut app, req).await;
+
+    assert_eq!(
+        res.status(),
+        401,
+        "/1.0/sync/1.5 should require an OAuth token"
+    );
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "status": "invalid-credentials",
+            "errors": [{"location": "body", "name": "", "description": "Unauthorized"}],
+        })
+    );
+}
+
+#[cfg(test)]
+const TEST_JWKS: &str = r#"{"keys": [{"n": "nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA-kzeVOVpVWwkWdVha4s38XM_pa_yr47av7-z3VTmvDRyAHcaT92whREFpLv9cj5lTeJSibyr_Mrm_YtjCZVWgaOYIhwrXwKLqPr_11inWsAkfIytvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0e-lf4s4OxQawWD79J9_5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWbV6L11BWkpzGXSW4Hv43qa-GSYOD2QU68Mb59oSk2OB-BtOLpJofmbGEGgvmwyCI9Mw", "e": "AQAB"}]}"#;
+
+#[cfg(test)]
+fn test_request(user: &str) -> actix_web::test::TestRequest {
+    use crate::token::{Claims, SigningKeys};
+    use actix_web::test;
+    use chrono::Utc;
+
+    let now = Utc::now().timestamp();
+    let token = SigningKeys::test_keys()
+        .sign(&Claims {
+            sub: user.to_owned(),
+            iss: "https://accounts.firefox.com".to_owned(),
+            aud: vec![],
+            scope: crate::oauth::SYNC_SCOPE.to_owned(),
+            client_id: "bhj4".to_owned(),
+            iat: now,
+            exp: now + 300,
+            generation: None,
+            profile_changed_at: None,
+        })
+        .unwrap();
+    test::TestRequest::get()
+        .uri("/1.0/sync/1.5")
+        .header("Authorization", format!("Bearer {}", token))
+}
+
+#[cfg(test)]
+fn key_id(keys_changed_at: i64, client_state: &str) -> String {
+    format_key_id(keys_changed_at, &hex::decode(client_state).unwrap())
+}
+
+#[actix_rt::test]
+async fn test_sqlite_token() {
+    use super::*;
+    use crate::db::sqlite::SqliteDbPool;
+    use crate::settings::Settings;
+    use actix_web::test;
+
+    // A fresh database has the Sync service and a node to assign users to.
+    let path = std::env::temp_dir().join(format!("tokenserver-{}.db", uuid::Uuid::new_v4()));
+    let db_pool = SqliteDbPool::from_settings(&Settings {
+        database_url: format!("sqlite://{}", path.display()),
+        service_entry: Some("https://example.com".to_owned()),
+        ..Settings::default()
+    })
+    .unwrap();
+    let state = ServerState {
+        db_pool: Box::new(db_pool),
+        ..test_state(serde_json::from_str(TEST_JWKS).unwrap())
+    };
+    let mut app = test_app(state).await;
+
+    let req = test_request("test_user")
+        .header("X-KeyID", "1234-qqo")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["api_endpoint"], "https://example.com/1.5/1");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[actix_rt::test]
+async fn test_token() {
+    use actix_web::test;
+
+    let mut app = test_app(test_state(serde_json::from_str(TEST_JWKS).unwrap())).await;
+
+    let req = test_request("test_user")
+        .header("X-KeyID", "1234-qqo")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 200);
+
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["uid"], 1);
+    assert_eq!(body["api_endpoint"], "https://example.com/1.5/1");
+    assert_eq!(body["duration"], 3600);
+    assert_eq!(body["node_type"], "mysql");
+    assert_eq!(
+        body["hashed_fxa_uid"],
+        fxa_metrics_hash("test_user", "foo").as_str()
+    );
+    let id = body["id"].as_str().unwrap();
+    let payload = tokenlib::parse_token(id, "Ted Koppel is a robot").unwrap();
+    assert_eq!(payload.uid, 1);
+    assert_eq!(payload.node, "https://example.com");
+    assert_eq!(payload.fxa_uid, "test_user");
+    assert_eq!(payload.fxa_kid, "0000000001234-qqo");
+    assert_eq!(
+        body["key"],
+        tokenlib::get_derived_secret(id, "Ted Koppel is a robot")
+            .unwrap()
+            .as_str()
+    );
+}
+
+#[actix_rt::test]
+async fn test_token_keeps_assignment() {
+    use actix_web::test;
+
+    let mut app = test_app(test_state(serde_json::from_str(TEST_JWKS).unwrap())).await;
+
+    for (user, uid) in &[("user_a", 1), ("user_b", 2), ("user_a", 1)] {
+        let req = test_request(user)
+            .header("X-KeyID", key_id(1, "aaaa"))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["uid"], *uid, "uid for {}", user);
+        assert_eq!(
+            body["api_endpoint"],
+            format!("https://example.com/1.5/{}", uid)
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn test_client_state_change() {
+    use actix_web::test;
+
+    let mut app = test_app(test_state(serde_json::from_str(TEST_JWKS).unwrap())).await;
+
+    // A new client state gets a new uid on the same node.
+    for (keys_changed_at, client_state, uid) in &[(1, "aaaa", 1), (1, "aaaa", 1), (2, "bbbb", 2)] {
+        let req = test_request("test_user")
+            .header("X-KeyID", key_id(*keys_changed_at, client_state))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["uid"], *uid, "uid for {}", client_state);
+        assert_eq!(
+            body["api_endpoint"],
+            format!("https://example.com/1.5/{}", uid)
+        );
+    }
+
+    for (keys_changed_at, client_state, description) in &[
+        (3, "aaaa", "Unacceptable client-state value stale value"),
+        (
+            2,
+            "cccc",
+            "Unacceptable client-state value new value with no keys_changed_at change",
+        ),
+    ] {
+        let req = test_request("test_user")
+            .header("X-KeyID", key_id(*keys_changed_at, client_state))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["status"], "invalid-client-state");
+        assert_eq!(body["errors"][0]["location"], "header");
+        assert_eq!(body["errors"][0]["name"], "X-Client-State");
+        assert_eq!(body["errors"][0]["description"], *description);
+        assert_eq!(body["errors"][0]["new_users_required_state"], "bbbb");
+    }
+
+    // Going back on keys_changed_at is rejected too.
+    let req = test_request("test_user")
+        .header("X-KeyID", key_id(1, "bbbb"))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 401);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["status"], "invalid-keysChangedAt");
+}
+
+#[actix_rt::test]
+async fn test_invalid_key_id() {
+    use actix_web::test;
+
+    let mut app = test_app(test_state(serde_json::from_str(TEST_JWKS).unwrap())).await;
+
+    let requests = vec![
+        (test_request("test_user"), "invalid-credentials"),
+        (
+            test_request("test_user").header("X-KeyID", "1234"),
+            "invalid-keyID",
+        ),
+        (
+            test_request("test_user").header("X-KeyID", "abc-qqo"),
+            "invalid-keyID",
+        ),
+        (
+            test_request("test_user")
+                .header("X-KeyID", "1234-qqo")
+                .header("X-Client-State", "bbbb"),
+            "invalid-client-state",
+        ),
+    ];
+    for (req, status) in requests {
+        let res = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["status"], status);
+    }
+
+    // A matching X-Client-State is fine.
+    let req = test_request("test_user")
+        .header("X-KeyID", "1234-qqo")
+        .header("X-Client-State", "aaaa")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 200);
+}
+
+#[actix_rt::test]
+async fn test_malformed_client_state() {
+    use actix_web::test;
+
+    let mut app = test_app(test_state(serde_json::from_str(TEST_JWKS).unwrap())).await;
+
+    let req = test_request("test_user")
+        .header("X-KeyID", "1234-qqo")
+        .header("X-Client-State", "12345678~90ab")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 401);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["status"], "invalid-client-state");
+    assert_eq!(body["errors"][0]["location"], "header");
+    assert_eq!(body["errors"][0]["name"], "X-Client-State");
+}
+
+#[actix_rt::test]
+async fn test_assign_user_generation() {
+    use crate::db::{memory::MemoryDbPool, DbPool};
+    use crate::settings::Settings;
+
+    let pool = MemoryDbPool::new(&Settings {
+        service_entry: Some("https://example.com".to_owned()),
+        ..Settings::default()
+    })
+    .unwrap();
+    let db = pool.get().unwrap();
+    let assign = |generation, keys_changed_at, client_state: &str| {
+        let req = TokenserverRequest {
+            fxa_uid: "test_user".to_owned(),
+            email: "test_user@api.accounts.firefox.com".to_owned(),
+            client_state: client_state.to_owned(),
+            generation,
+            keys_changed_at,
+            device_id: None,
+        };
+        let db = &*db;
+        async move { assign_user(db, &req, true, 1000).await }
+    };
+    let kind = |result: ApiResult<Assignment>| result.unwrap_err().kind().to_string();
+
+    let user = assign(Some(2), Some(2), "aaaa").await.unwrap();
+    assert_eq!(
+        (user.uid, user.generation, user.keys_changed_at),
+        (1, 2, Some(2))
+    );
+
+    // Newer values are recorded in place.
+    let user = assign(Some(3), None, "aaaa").await.unwrap();
+    assert_eq!(
+        (user.uid, user.generation, user.keys_changed_at),
+        (1, 3, Some(2))
+    );
+
+    // Older ones are rejected, as are keys changed after the generation.
+    assert_eq!(
+        kind(assign(Some(1), None, "aaaa").await),
+        ApiErrorKind::InvalidGeneration.to_string()
+    );
+    assert_eq!(
+        kind(assign(None, Some(1), "aaaa").await),
+        ApiErrorKind::InvalidKeysChangedAt.to_string()
+    );
+    assert_eq!(
+        kind(assign(Some(3), Some(4), "aaaa").await),
+        ApiErrorKind::InvalidKeysChangedAt.to_string()
+    );
+
+    // A new client state needs the keys to have changed.
+    assert_eq!(
+        kind(assign(Some(3), None, "bbbb").await),
+        "Unacceptable client-state value new value with no generation change"
+    );
+    assert_eq!(
+        kind(assign(None, Some(2), "bbbb").await),
+        "Unacceptable client-state value new value with no keys_changed_at change"
+    );
+    let user = assign(Some(4), Some(4), "bbbb").await.unwrap();
+    assert_eq!(
+        (user.uid, user.generation, user.keys_changed_at),
+        (2, 4, Some(4))
+    );
+}
+
+#[actix_rt::test]
+async fn test_unsupported_application() {
+    use actix_web::test;
+
+    let mut app = test_app(test_state(serde_json::from_str(TEST_JWKS).unwrap())).await;
+
+    for uri in &["/1.0/sync/1.1", "/1.0/foo/1.5"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 404);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["status"], "unsupported-application");
+        assert_eq!(body["errors"][0]["location"], "url");
+        assert_eq!(body["errors"][0]["name"], "application");
+    }
+}
+
+#[actix_rt::test]
+async fn test_new_users_disabled() {
+    use super::*;
+    use actix_web::test;
+
+    let state = ServerState {
+        allow_new_users: false,
+        ..test_state(serde_json::from_str(TEST_JWKS).unwrap())
+    };
+    let mut app = test_app(state).await;
+
+    let req = test_request("test_user")
+        .header("X-KeyID", key_id(1, "aaaa"))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 401);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["status"], "new-users-disabled");
+}
+
+#[actix_rt::test]
+async fn test_no_node_available() {
     use super::*;
+    use crate::db::memory::MemoryDbPool;
+    use crate::settings::Settings;
     use actix_web::test;
+
+    // No service_entry, so no nodes.
+    let state = ServerState {
+        db_pool: Box::new(MemoryDbPool::new(&Settings::default()).unwrap()),
+        ..test_state(serde_json::from_str(TEST_JWKS).unwrap())
+    };
+    let mut app = test_app(state).await;
+
+    let req = test_request("test_user")
+        .header("X-KeyID", key_id(1, "aaaa"))
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 503);
+    assert_eq!(res.headers().get("Retry-After").unwrap(), "10");
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "status": "error",
+            "errors": [{"location": "internal", "name": "", "description": "Service Unavailable"}],
+        })
+    );
+}
+
+#[actix_rt::test]
+async fn test_browserid() {
+    use super::*;
+    use crate::browserid::BrowserIdVerifier;
+    use actix_web::test;
+    use mockito::mock;
+
+    let state = ServerState {
+        browserid_verifier: Some(
+            BrowserIdVerifier::new(
+                &format!("{}/v2", mockito::server_url()),
+                "https://token.example.com",
+                "api.accounts.firefox.com",
+                std::time::Duration::from_secs(5),
+            )
+            .unwrap(),
+        ),
+        ..test_state(crate::oauth::JWK { keys: vec![] })
+    };
+    let mut app = test_app(state).await;
+
+    let verified = mock("POST", "/v2")
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{"status": "okay", "email": "test_user@api.accounts.firefox.com",
+                "issuer": "api.accounts.firefox.com",
+                "idpClaims": {"fxa-generation": 1234, "fxa-keysChangedAt": 1200, "fxa-deviceId": "device"}}"#,
+        )
+        .create();
+    let req = test::TestRequest::get()
+        .uri("/1.0/sync/1.5")
+        .header("Authorization", "BrowserID cert~assertion")
+        .header("X-Client-State", "aaaa")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    verified.assert();
+    assert_eq!(res.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["uid"], 1);
+    assert_eq!(
+        body["hashed_device_id"],
+        hash_device_id("test_user", "device", "foo").as_str()
+    );
+    let payload =
+        tokenlib::parse_token(body["id"].as_str().unwrap(), "Ted Koppel is a robot").unwrap();
+    assert_eq!(payload.fxa_uid, "test_user");
+    assert_eq!(payload.fxa_kid, "0000000001200-qqo");
+    drop(verified);
+
+    let rejected = mock("POST", "/v2")
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"status": "failure", "reason": "assertion has expired"}"#)
+        .create();
+    let req = test::TestRequest::get()
+        .uri("/1.0/sync/1.5")
+        .header("Authorization", "BrowserID cert~assertion")
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    rejected.assert();
+    assert_eq!(res.status(), 401);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["status"], "invalid-credentials");
+}
+
+#[actix_rt::test]
+async fn test_heartbeat() {
+    use super::*;
+    use crate::oauth::RemoteVerifier;
+    use actix_web::test;
+    use mockito::mock;
+
+    let remote_verifier = RemoteVerifier::new(
+        &mockito::server_url(),
+        "example.com",
+        std::time::Duration::from_secs(5),
+    )
+    .unwrap();
     let mut app = test::init_service(
-        App::new().service(web::resource("/1.0/sync/1.5").route(web::get().to(get_handler))),
+        App::new()
+            .data(ServerState {
+                remote_verifier: Some(remote_verifier.clone()),
+                ..test_state(serde_json::from_str(TEST_JWKS).unwrap())
+            })
+            .service(web::resource("/__heartbeat__").route(web::get().to(heartbeat))),
     )
     .await;
 
-    let req = test::TestRequest::get().uri("/1.0/sync/1.5").to_request();
+    let lbheartbeat = mock("GET", "/__lbheartbeat__").create();
+    let req = test::TestRequest::get().uri("/__heartbeat__").to_request();
+    let res = test::call_service(&mut app, req).await;
+    lbheartbeat.assert();
+    assert_eq!(res.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "status": "ok",
+            "version": env!("CARGO_PKG_VERSION"),
+            "database": "ok",
+            "jwks": "ok",
+            "oauth_verifier": "ok",
+        })
+    );
+    drop(lbheartbeat);
+
+    // No keys, and an OAuth server that's down.
+    let mut app = test::init_service(
+        App::new()
+            .data(ServerState {
+                remote_verifier: Some(remote_verifier),
+                ..test_state(crate::oauth::JWK { keys: vec![] })
+            })
+            .service(web::resource("/__heartbeat__").route(web::get().to(heartbeat))),
+    )
+    .await;
+    let lbheartbeat = mock("GET", "/__lbheartbeat__").with_status(502).create();
+    let req = test::TestRequest::get().uri("/__heartbeat__").to_request();
     let res = test::call_service(&mut app, req).await;
+    lbheartbeat.assert();
+    assert_eq!(res.status(), 503);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["database"], "ok");
+    assert_eq!(body["jwks"], "error");
+    assert_eq!(body["oauth_verifier"], "error");
+}
+
+#[actix_rt::test]
+async fn test_metrics() {
+    use super::middleware::{metrics::MetricsWrapper, tags::TagsWrapper};
+    use super::*;
+    use crate::metrics::SpyMetricSink;
+    use actix_web::test;
+
+    let sink = SpyMetricSink::default();
+    let mut app = test::init_service(
+        App::new()
+            .data(ServerState {
+                metrics: std::sync::Arc::new(sink.client()),
+                ..test_state(serde_json::from_str(TEST_JWKS).unwrap())
+            })
+            .wrap(MetricsWrapper)
+            .wrap(TagsWrapper::new(&[TOKEN_ROUTE]))
+            .service(web::resource(TOKEN_ROUTE).route(web::get().to(get_handler))),
+    )
+    .await;
+
+    for (keys_changed_at, client_state) in &[(1, "aaaa"), (1, "aaaa"), (2, "bbbb")] {
+        let req = test_request("test_user")
+            .header("X-KeyID", key_id(*keys_changed_at, client_state))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), 200);
+    }
+    let req = test_request("test_user").to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), 401);
+
+    let names = sink.names();
+    let count = |name: &str| names.iter().filter(|n| n.starts_with(name)).count();
+    assert_eq!(count("token.success:1"), 3);
+    assert_eq!(count("token.new_user:1"), 1);
+    assert_eq!(count("token.replaced_user:1"), 1);
+    assert_eq!(count("request.status.200:1"), 3);
+    assert_eq!(count("request.status.401:1"), 1);
+    assert_eq!(count("request.latency:"), 4);
+
+    let metrics = sink.metrics.lock().unwrap();
+    let error = metrics
+        .iter()
+        .find(|m| m.starts_with("request.error:1"))
+        .unwrap();
+    assert!(error.contains("error:invalid_credentials"), "{}", error);
+    let latency = metrics
+        .iter()
+        .rfind(|m| m.starts_with("request.latency:"))
+        .unwrap();
+    assert!(latency.contains("status:4xx"), "{}", latency);
+    assert!(
+        latency.contains(&format!("uri.route:{}", TOKEN_ROUTE)),
+        "{}",
+        latency
+    );
+}
+
+#[actix_rt::test]
+async fn test_gauges() {
+    use crate::metrics::{send_gauges, SpyMetricSink};
+
+    let sink = SpyMetricSink::default();
+    let state = ServerState {
+        metrics: std::sync::Arc::new(sink.client()),
+        ..test_state(serde_json::from_str(TEST_JWKS).unwrap())
+    };
+    send_gauges(&state).await;
 
-    assert_eq!(res.status(), 200, "/1.0/sync/1.5 should return 200");
+    let names = sink.names();
+    for name in &[
+        "db.pool.connections.active:0",
+        "db.pool.connections.idle:0",
+        "nodes.capacity:2147483647",
+        "nodes.current_load:0",
+        "nodes.available:2147483647",
+    ] {
+        assert!(names.iter().any(|n| n == name), "{} in {:?}", name, names);
+    }
+    // Configured keys are never stale.
+    assert!(!names.iter().any(|n| n.starts_with("jwks.age:")));
 }
@@ -0,0 +1,84 @@
+//! Times every request and counts the statuses they're answered with.
+//!
+//! Each request is sent as `request.latency`, tagged with its status class
+//! (`2xx`, `4xx`, ...) on top of the request's tags, and counted as
+//! `request.status.<code>`. Errors are also counted as `request.error`,
+//! tagged with the kind of error.
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::tags::Tags;
+
+#[derive(Debug, Default)]
+pub struct MetricsWrapper;
+
+impl<S, B> Transform<S> for MetricsWrapper
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MetricsWrapperMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MetricsWrapperMiddleware { service })
+    }
+}
+
+#[derive(Debug)]
+pub struct MetricsWrapperMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for MetricsWrapperMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let mut timer = Metrics::from(&sreq);
+        timer.start_timer("request.latency", None);
+        let fut = self.service.call(sreq);
+        Box::pin(async move {
+            let res = fut.await?;
+            let status = res.status().as_u16();
+            timer.tag_timer("status", &format!("{}xx", status / 100));
+            // The latency is sent when the timer is dropped.
+            drop(timer);
+
+            Metrics::from(res.request()).incr(&format!("request.status.{}", status));
+            if let Some(error) = res.response().error() {
+                let label = match error.as_error::<ApiError>() {
+                    Some(error) => error.kind().metric_label(),
+                    None => "other",
+                };
+                let mut tags = Tags::default();
+                tags.tags.insert("error".to_owned(), label.to_owned());
+                Metrics::from(res.request()).incr_with_tags("request.error", Some(tags));
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,4 @@
+//! Middleware wrapping every request.
+pub mod metrics;
+pub mod sentry;
+pub mod tags;
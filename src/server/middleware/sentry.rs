@@ -0,0 +1,121 @@
+//! Reports server errors to Sentry, along with the request's tags.
+//!
+//! Only errors with a 5xx status are reported: the 4xx ones are expected
+//! answers to bad requests, not problems with the server.
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use sentry::integrations::failure::event_from_fail;
+
+use crate::error::ApiError;
+use crate::tags::Tags;
+
+#[derive(Debug, Default)]
+pub struct SentryWrapper;
+
+impl<S, B> Transform<S> for SentryWrapper
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SentryWrapperMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SentryWrapperMiddleware { service })
+    }
+}
+
+#[derive(Debug)]
+pub struct SentryWrapperMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for SentryWrapperMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let fut = self.service.call(sreq);
+        Box::pin(async move {
+            let res = fut.await?;
+            if let Some(error) = res.response().error() {
+                if let Some(error) = error.as_error::<ApiError>() {
+                    if error.is_reportable() {
+                        report(error, Tags::from(res.request()));
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+fn report(error: &ApiError, tags: Tags) {
+    let mut event = event_from_fail(error);
+    event.tags = tags.clone().tag_tree();
+    event.extra = tags.extra_tree();
+    sentry::capture_event(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiErrorKind;
+    use crate::server::handlers::test_error;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn unauthorized() -> Result<HttpResponse, ApiError> {
+        Err(ApiErrorKind::InvalidCredentials.into())
+    }
+
+    #[test]
+    fn test_reports_server_errors() {
+        let events = sentry::test::with_captured_events(|| {
+            actix_rt::System::new("test").block_on(async {
+                let mut app = test::init_service(
+                    App::new()
+                        .wrap(SentryWrapper)
+                        .service(web::resource("/__error__").route(web::get().to(test_error)))
+                        .service(web::resource("/unauthorized").route(web::get().to(unauthorized))),
+                )
+                .await;
+                for (uri, status) in &[("/unauthorized", 401), ("/__error__", 500)] {
+                    let req = test::TestRequest::get().uri(uri).to_request();
+                    let res = test::call_service(&mut app, req).await;
+                    assert_eq!(res.status(), *status);
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].exception.values[0].value.as_deref(),
+            Some("Oh Noes!")
+        );
+        assert_eq!(
+            events[0].tags.get("uri.method").map(String::as_str),
+            Some("GET")
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Tags each request once, up front, so metrics, logging and Sentry all see
+//! the same tags.
+//!
+//! Besides what `Tags::from_request_head` finds, requests are tagged with the
+//! route they're for as `uri.route`. That's the route's pattern rather than
+//! the path, so there's one value per route.
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{ResourceDef, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use futures::future::{ok, Ready};
+
+use crate::tags::Tags;
+
+/// What `uri.route` is for requests that match no route.
+const UNKNOWN_ROUTE: &str = "unknown";
+
+#[derive(Clone, Debug)]
+pub struct TagsWrapper {
+    routes: Rc<Vec<(String, ResourceDef)>>,
+}
+
+impl TagsWrapper {
+    /// Tag requests with whichever of `routes` they match.
+    pub fn new(routes: &[&str]) -> Self {
+        Self {
+            routes: Rc::new(
+                routes
+                    .iter()
+                    .map(|route| ((*route).to_owned(), ResourceDef::new(*route)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for TagsWrapper
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TagsWrapperMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TagsWrapperMiddleware {
+            service,
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct TagsWrapperMiddleware<S> {
+    service: S,
+    routes: Rc<Vec<(String, ResourceDef)>>,
+}
+
+impl<S, B> Service for TagsWrapperMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let mut tags = Tags::from_request_head(sreq.head());
+        let route = self
+            .routes
+            .iter()
+            .find(|(_, def)| def.is_match(sreq.path()))
+            .map(|(route, _)| route.as_str())
+            .unwrap_or(UNKNOWN_ROUTE);
+        tags.tags.insert("uri.route".to_owned(), route.to_owned());
+        sreq.extensions_mut().insert(tags);
+        self.service.call(sreq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest};
+
+    async fn route(req: HttpRequest) -> String {
+        Tags::from(&req).get("uri.route")
+    }
+
+    #[actix_rt::test]
+    async fn test_route() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(TagsWrapper::new(&[
+                    "/1.0/{application}/{version}",
+                    "/__heartbeat__",
+                ]))
+                .service(web::resource("/1.0/{application}/{version}").route(web::get().to(route)))
+                .service(web::resource("/__heartbeat__").route(web::get().to(route)))
+                .default_service(web::route().to(route)),
+        )
+        .await;
+
+        for (uri, expected) in &[
+            ("/1.0/sync/1.5", "/1.0/{application}/{version}"),
+            ("/__heartbeat__", "/__heartbeat__"),
+            ("/1.0/sync", UNKNOWN_ROUTE),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let body = test::read_response(&mut app, req).await;
+            assert_eq!(&body[..], expected.as_bytes(), "{}", uri);
+        }
+    }
+}
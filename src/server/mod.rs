@@ -2,24 +2,82 @@
 
 mod extractors;
 mod handlers;
+mod middleware;
+use std::sync::Arc;
+use std::time::Duration;
+
 use actix_cors::Cors;
 use actix_web::{
     dev, http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpRequest,
-    HttpResponse, HttpServer,
+    HttpResponse, HttpServer, Route,
 };
 use cadence::StatsdClient;
 
 use handlers::get_handler;
 
-use crate::error::ApiError;
+use crate::browserid::BrowserIdVerifier;
+use crate::db::{self, DbPool};
+use crate::error::{ApiError, ApiErrorKind};
+use crate::jwks::JwksProvider;
 use crate::metrics;
+use crate::oauth::{ClaimValidation, RemoteVerifier};
+use crate::secrets::Secrets;
 use crate::settings::Settings;
+use crate::token::SigningKeys;
+
+/// The route tokens are issued from.
+pub(crate) const TOKEN_ROUTE: &str = "/1.0/{application}/{version}";
+
+/// Every route and how it's handled. The app serves these, and tags requests
+/// with the one they're for.
+fn routes() -> Vec<(&'static str, Route)> {
+    vec![
+        // Dockerflow
+        ("/__heartbeat__", web::get().to(handlers::heartbeat)),
+        (
+            "/__lbheartbeat__",
+            web::get().to(|_: HttpRequest| {
+                // used by the load balancers, just return OK.
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body("{}")
+            }),
+        ),
+        (TOKEN_ROUTE, web::get().to(get_handler)),
+        (
+            "/__version__",
+            web::get().to(|_: HttpRequest| {
+                // return the contents of the version.json file created by circleci
+                // and stored in the docker root
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(include_str!("../../version.json"))
+            }),
+        ),
+        ("/__error__", web::get().to(handlers::test_error)),
+    ]
+}
 
 #[derive(Clone, Debug)]
 pub struct ServerState {
     /// Server Data
-    pub metrics: Box<StatsdClient>,
+    pub metrics: Arc<StatsdClient>,
     pub port: u16,
+    pub db_pool: Box<dyn DbPool>,
+    pub jwks: JwksProvider,
+    /// How the claims of OAuth tokens verified with the JWKS are checked.
+    pub claim_validation: ClaimValidation,
+    /// Verifies the OAuth tokens the JWKS can't.
+    pub remote_verifier: Option<RemoteVerifier>,
+    /// The configured `privkey`/`pubkey` pair, if any.
+    pub signing_keys: Option<SigningKeys>,
+    /// Verifies BrowserID assertions, when they're accepted.
+    pub browserid_verifier: Option<BrowserIdVerifier>,
+    pub node_type: String,
+    pub token_duration: u64,
+    pub secrets: Arc<Secrets>,
+    pub fxa_metrics_hash_secret: String,
+    pub allow_new_users: bool,
 }
 
 pub struct Server;
@@ -27,44 +85,77 @@ pub struct Server;
 impl Server {
     pub fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
         let metrics = metrics::metrics_from_opts(&settings)?;
+        let db_pool = db::pool_from_settings(&settings)?;
         let port = settings.port;
+        let jwks = JwksProvider::from_settings(&settings)
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid jwks: {}", e)))?;
+        jwks.spawn_refresh();
+        let remote_verifier = RemoteVerifier::from_settings(&settings)?;
+        let browserid_verifier = BrowserIdVerifier::from_settings(&settings)?;
+        let signing_keys = SigningKeys::from_settings(&settings)
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid signing keys: {}", e)))?;
+        let secrets = match settings.secrets_file {
+            Some(ref path) => Secrets::from_file(path)
+                .map_err(|e| ApiErrorKind::Internal(format!("Invalid secrets_file: {}", e)))?,
+            None => Secrets::with_shared_secret(&settings.shared_secret),
+        };
         let state = ServerState {
-            metrics: Box::new(metrics),
+            metrics: Arc::new(metrics),
             port,
+            db_pool,
+            jwks,
+            claim_validation: ClaimValidation::from_settings(&settings),
+            remote_verifier,
+            signing_keys,
+            browserid_verifier,
+            node_type: settings.node_type.clone(),
+            token_duration: settings.token_duration,
+            secrets: Arc::new(secrets),
+            fxa_metrics_hash_secret: settings.fxa_metrics_hash_secret.clone(),
+            allow_new_users: settings.allow_new_users,
         };
+        metrics::spawn_gauges(
+            state.clone(),
+            Duration::from_secs(settings.statsd_gauge_interval),
+        );
 
         let server = HttpServer::new(move || {
-            App::new()
+            let routes = routes();
+            let paths: Vec<&str> = routes.iter().map(|(path, _)| *path).collect();
+            let app = App::new()
                 .data(state.clone())
+                .wrap(middleware::sentry::SentryWrapper)
+                .wrap(middleware::metrics::MetricsWrapper)
+                .wrap(middleware::tags::TagsWrapper::new(&paths))
                 .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
-                .wrap(Cors::default())
-                // TODO: Add endpoints and handlers here.
-                //
-                // Dockerflow
-                //.service(web::resource("/__heartbeat__").route(web::get().to(handlers::heartbeat)))
-                .service(web::resource("/__lbheartbeat__").route(web::get().to(
-                    |_: HttpRequest| {
-                        // used by the load balancers, just return OK.
-                        HttpResponse::Ok()
-                            .content_type("application/json")
-                            .body("{}")
-                    },
-                )))
-                .service(web::resource("/1.0/sync/1.5").route(web::get().to(get_handler)))
-                .service(
-                    web::resource("/__version__").route(web::get().to(|_: HttpRequest| {
-                        // return the contents of the version.json file created by circleci
-                        // and stored in the docker root
-                        HttpResponse::Ok()
-                            .content_type("application/json")
-                            .body(include_str!("../../version.json"))
-                    })),
-                )
-            //.service(web::resource("/__error__").route(web::get().to(handlers::test_error)))
+                .wrap(Cors::default());
+            routes.into_iter().fold(app, |app, (path, route)| {
+                app.service(web::resource(path).route(route))
+            })
         })
         .bind(format!("{}:{}", settings.host, settings.port))
-        .expect("Could not launch server")
+        .map_err(|e| ApiErrorKind::Internal(format!("Could not bind: {}", e)))?
         .run();
         Ok(server)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_invalid_signing_keys() {
+        let settings = Settings {
+            database_url: "memory://".to_owned(),
+            jwks: Some(r#"{"keys": []}"#.to_owned()),
+            privkey: Some(include_str!("../private_rsa_key.pem").to_owned()),
+            pubkey: Some(r#"{"n": "AQAB", "e": "AQAB"}"#.to_owned()),
+            ..Settings::default()
+        };
+        match Server::with_settings(settings) {
+            Ok(_) => panic!("a mismatched key pair was accepted"),
+            Err(e) => assert!(e.to_string().contains("Invalid signing keys"), "{}", e),
+        }
+    }
+}
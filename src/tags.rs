@@ -2,6 +2,7 @@ use std::collections::{BTreeMap, HashMap};
 
 use actix_web::{
     dev::{Payload, RequestHead},
+    http::header::USER_AGENT,
     Error, FromRequest, HttpRequest,
 };
 use futures::future;
@@ -42,7 +43,6 @@ impl Serialize for Tags {
     }
 }
 
-#[allow(dead_code)]
 fn insert_if_not_empty(label: &str, val: &str, tags: &mut HashMap<String, String>) {
     if !val.is_empty() {
         tags.insert(label.to_owned(), val.to_owned());
@@ -50,15 +50,31 @@ fn insert_if_not_empty(label: &str, val: &str, tags: &mut HashMap<String, String
 }
 
 impl Tags {
+    /// Tag a request with its method and what its User-Agent says about the
+    /// client. The full User-Agent is kept as an extra, so it only goes to
+    /// Sentry.
     pub fn from_request_head(req_head: &RequestHead) -> Tags {
-        // Return an Option<> type because the later consumers (ApiErrors) presume that
-        // tags are optional and wrapped by an Option<> type.
         let mut tags = HashMap::new();
         tags.insert("uri.method".to_owned(), req_head.method.to_string());
-        Tags {
-            tags,
-            extra: HashMap::new(),
+        let mut extra = HashMap::new();
+        let ua = req_head
+            .headers
+            .get(USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        if !ua.is_empty() {
+            let parsed = UserAgent::parse(ua);
+            tags.insert(
+                "ua.browser.family".to_owned(),
+                parsed.browser_family.to_owned(),
+            );
+            insert_if_not_empty("ua.browser.ver", &parsed.browser_version, &mut tags);
+            tags.insert("ua.os.family".to_owned(), parsed.os_family.to_owned());
+            insert_if_not_empty("ua.os.ver", &parsed.os_version, &mut tags);
+            tags.insert("ua.device".to_owned(), parsed.device.to_owned());
+            extra.insert("ua".to_owned(), ua.to_owned());
         }
+        Tags { tags, extra }
     }
 
     pub fn with_tags(tags: HashMap<String, String>) -> Tags {
@@ -112,21 +128,24 @@ impl slog::KV for Tags {
     }
 }
 
+/// The tags the tagging middleware stored for the request, or those of its
+/// head if it didn't run.
+impl From<&HttpRequest> for Tags {
+    fn from(req: &HttpRequest) -> Self {
+        match req.extensions().get::<Tags>() {
+            Some(tags) => tags.clone(),
+            None => Tags::from_request_head(req.head()),
+        }
+    }
+}
+
 impl FromRequest for Tags {
     type Config = ();
     type Error = Error;
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let tags = {
-            let exts = req.extensions();
-            match exts.get::<Tags>() {
-                Some(t) => t.clone(),
-                None => Tags::from_request_head(req.head()),
-            }
-        };
-
-        future::ok(tags)
+        future::ok(Tags::from(req))
     }
 }
 
@@ -141,3 +160,182 @@ impl Into<BTreeMap<String, String>> for Tags {
         result
     }
 }
+
+/// What a User-Agent says about the client, as far as the Firefox, Sync and
+/// common browser User-Agents go. Anything else is "Other".
+#[derive(Debug, PartialEq)]
+struct UserAgent {
+    browser_family: &'static str,
+    browser_version: String,
+    os_family: &'static str,
+    os_version: String,
+    /// "desktop", "mobile", "tablet" or "other".
+    device: &'static str,
+}
+
+impl UserAgent {
+    fn parse(ua: &str) -> Self {
+        let (browser_family, browser_version) = if let Some(version) =
+            version_after(ua, "Firefox-iOS-Sync/")
+                .or_else(|| version_after(ua, "Firefox-iOS-FxA/"))
+                .or_else(|| version_after(ua, "FxiOS/"))
+                .or_else(|| version_after(ua, "Firefox AndroidSync "))
+        {
+            ("Firefox", version)
+        } else if let Some(version) = version_after(ua, "Edg/") {
+            ("Edge", version)
+        } else if let Some(version) = version_after(ua, "Firefox/") {
+            ("Firefox", version)
+        } else if let Some(version) =
+            version_after(ua, "Chrome/").or_else(|| version_after(ua, "CriOS/"))
+        {
+            ("Chrome", version)
+        } else if ua.contains("Safari/") {
+            ("Safari", version_after(ua, "Version/").unwrap_or_default())
+        } else {
+            ("Other", String::new())
+        };
+
+        // iOS User-Agents claim to be "like Mac OS X", and Android ones to be
+        // Linux, so those go first.
+        let (os_family, os_version) = if let Some(version) = version_after(ua, "Windows NT ") {
+            ("Windows", version)
+        } else if ua.contains("Android") {
+            ("Android", version_after(ua, "Android ").unwrap_or_default())
+        } else if let Some(version) =
+            version_after(ua, "iPhone OS ").or_else(|| version_after(ua, "CPU OS "))
+        {
+            ("iOS", version)
+        } else if ua.contains("iPhone") || ua.contains("iPad") || ua.contains("iOS") {
+            ("iOS", String::new())
+        } else if let Some(version) = version_after(ua, "Mac OS X ") {
+            ("macOS", version)
+        } else if ua.contains("Linux") || ua.contains("X11") {
+            ("Linux", String::new())
+        } else {
+            ("Other", String::new())
+        };
+
+        let device = if ua.contains("iPad") || ua.contains("Tablet") {
+            "tablet"
+        } else if ua.contains("iPhone") || ua.contains("Mobile") || os_family == "Android" {
+            "mobile"
+        } else if ["Windows", "macOS", "Linux"].contains(&os_family) {
+            "desktop"
+        } else {
+            "other"
+        };
+
+        UserAgent {
+            browser_family,
+            browser_version,
+            os_family,
+            os_version,
+            device,
+        }
+    }
+}
+
+/// The major and minor parts of the version that follows `marker`, if any.
+/// Keeping only those keeps the number of distinct tag values down.
+fn version_after(ua: &str, marker: &str) -> Option<String> {
+    let start = ua.find(marker)? + marker.len();
+    let version: String = ua[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == '_')
+        .map(|c| if c == '_' { '.' } else { c })
+        .collect();
+    let parts: Vec<&str> = version
+        .split('.')
+        .filter(|part| !part.is_empty())
+        .take(2)
+        .collect();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_user_agent() {
+        let cases = vec![
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:85.0) Gecko/20100101 Firefox/85.0",
+                ("Firefox", "85.0", "macOS", "10.15", "desktop"),
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:84.0) Gecko/20100101 Firefox/84.0.2",
+                ("Firefox", "84.0", "Windows", "10.0", "desktop"),
+            ),
+            (
+                "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:85.0) Gecko/20100101 Firefox/85.0",
+                ("Firefox", "85.0", "Linux", "", "desktop"),
+            ),
+            (
+                "Mozilla/5.0 (Android 11; Mobile; rv:85.0) Gecko/85.0 Firefox/85.0",
+                ("Firefox", "85.0", "Android", "11", "mobile"),
+            ),
+            (
+                "Mozilla/5.0 (Android 9; Tablet; rv:68.0) Gecko/68.0 Firefox/68.0",
+                ("Firefox", "68.0", "Android", "9", "tablet"),
+            ),
+            (
+                "Firefox-iOS-Sync/31.0b12345 (iPhone; iPhone OS 14_2) (Firefox)",
+                ("Firefox", "31.0", "iOS", "14.2", "mobile"),
+            ),
+            (
+                "Firefox-iOS-FxA/24",
+                ("Firefox", "24", "iOS", "", "other"),
+            ),
+            (
+                "Firefox AndroidSync 1.68.0.1.0 (Firefox)",
+                ("Firefox", "1.68", "Android", "", "mobile"),
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 12_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/12.1.2 Mobile/15E148 Safari/604.1",
+                ("Safari", "12.1", "iOS", "12.5", "tablet"),
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/88.0.4324.150 Safari/537.36 Edg/88.0.705.63",
+                ("Edge", "88.0", "Windows", "10.0", "desktop"),
+            ),
+            ("curl/7.64.1", ("Other", "", "Other", "", "other")),
+        ];
+        for (ua, (browser_family, browser_version, os_family, os_version, device)) in cases {
+            assert_eq!(
+                UserAgent::parse(ua),
+                UserAgent {
+                    browser_family,
+                    browser_version: browser_version.to_owned(),
+                    os_family,
+                    os_version: os_version.to_owned(),
+                    device,
+                },
+                "{}",
+                ua
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_request_head() {
+        let ua = "Mozilla/5.0 (Android 11; Mobile; rv:85.0) Gecko/85.0 Firefox/85.0";
+        let req = TestRequest::with_header("User-Agent", ua).to_http_request();
+        let tags = Tags::from_request_head(req.head());
+        assert_eq!(tags.get("uri.method"), "GET");
+        assert_eq!(tags.get("ua.browser.family"), "Firefox");
+        assert_eq!(tags.get("ua.os.ver"), "11");
+        assert_eq!(tags.get("ua.device"), "mobile");
+        assert_eq!(tags.extra.get("ua").map(String::as_str), Some(ua));
+
+        let tags = Tags::from_request_head(TestRequest::default().to_http_request().head());
+        assert_eq!(tags.tags.len(), 1);
+        assert!(tags.extra.is_empty());
+    }
+}
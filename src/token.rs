@@ -3,12 +3,12 @@ use jsonwebtoken::{
     decode, encode, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::convert::From;
 use thiserror::Error;
 
 use crate::oauth::Key;
-use crate::settings;
+use crate::settings::Settings;
 
 #[derive(Error, Debug)]
 pub enum TokenError {
@@ -16,72 +16,194 @@ pub enum TokenError {
     InvalidToken,
     #[error("Issuer is Invalid")]
     InvalidIssuer, // issuer: api.accounts.firefox.com
+    #[error("Audience is Invalid")]
+    InvalidAudience,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Signature is Invalid")]
+    InvalidSignature,
+    #[error("Claims are Invalid: {0}")]
+    InvalidClaims(String),
     #[error("some other error")]
     Unknown,
 }
 
+/// Why the configured signing keys couldn't be loaded.
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("Could not read {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("Invalid private key: {0}")]
+    InvalidPrivateKey(jsonwebtoken::errors::Error),
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("The private and public keys don't match")]
+    Mismatch,
+    #[error("Both a private and a public key are required")]
+    Incomplete,
+}
+
 // convert the error more appropriately.
 impl From<jsonwebtoken::errors::Error> for TokenError {
     fn from(err: jsonwebtoken::errors::Error) -> Self {
         match err.kind() {
-            ErrorKind::InvalidToken => TokenError::InvalidToken,
+            ErrorKind::InvalidToken | ErrorKind::Base64(_) | ErrorKind::Utf8(_) => {
+                TokenError::InvalidToken
+            }
             ErrorKind::InvalidIssuer => TokenError::InvalidIssuer,
+            ErrorKind::InvalidAudience => TokenError::InvalidAudience,
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            ErrorKind::InvalidSignature => TokenError::InvalidSignature,
+            ErrorKind::Json(e) => TokenError::InvalidClaims(e.to_string()),
             _ => TokenError::Unknown,
         }
     }
 }
 
+/// The claims of an FxA OAuth access token.
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Claims {
-    pub user: String,
-    pub scope: Option<Vec<String>>,
+    /// The FxA uid.
+    pub sub: String,
+    pub iss: String,
+    #[serde(
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub aud: Vec<String>,
+    /// Space separated scopes.
+    pub scope: String,
     pub client_id: String,
     pub iat: i64,
     pub exp: i64,
-    pub issuer: String,
+    #[serde(
+        rename = "fxa-generation",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub generation: Option<i64>,
+    #[serde(
+        rename = "fxa-profileChangedAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub profile_changed_at: Option<i64>,
 }
 
-fn read_from_file(path: &str) -> Vec<u8> {
-    std::fs::read(path).unwrap_or_else(|error| {
-        if error.kind() == std::io::ErrorKind::NotFound {
-            panic!("File not found: {:?}", error)
-        } else {
-            panic!("Problem opening the file: {:?}", error)
-        }
-    })
+impl Claims {
+    pub fn scopes(&self) -> Vec<String> {
+        self.scope.split_whitespace().map(str::to_owned).collect()
+    }
 }
 
-pub fn verify_jwt_token(token: &str) -> Result<TokenData<Claims>, TokenError> {
-    let settings = settings::Settings::default();
-    let pubkey_path = settings.pubkey_path;
+/// JWTs may give a single audience as a string rather than an array.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(aud) => vec![aud],
+        OneOrMany::Many(aud) => aud,
+    })
+}
 
-    decode::<Claims>(
-        &token,
-        &DecodingKey::from_rsa_pem(read_from_file(&pubkey_path).as_slice()).unwrap(),
-        &Validation::new(Algorithm::RS256),
-    )
-    .map_err(From::from)
+/// The RSA key pair tokens are signed and verified with.
+#[derive(Clone, Debug)]
+pub struct SigningKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey<'static>,
 }
 
-pub fn verify_jwt_token_from_rsa(key: &Key, token: &str) -> Result<TokenData<Claims>, TokenError> {
-    decode::<Claims>(
-        &token,
-        &DecodingKey::from_rsa_components(&key.n, &key.e),
-        &Validation::new(Algorithm::RS256),
-    )
-    .map_err(From::from)
+impl SigningKeys {
+    /// Load a key pair from a PEM private key and either a PEM public key or
+    /// an RSA JWK.
+    pub fn new(privkey: &[u8], pubkey: &[u8]) -> Result<Self, KeyError> {
+        let encoding = EncodingKey::from_rsa_pem(privkey).map_err(KeyError::InvalidPrivateKey)?;
+        let is_jwk = pubkey.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{');
+        let decoding = if is_jwk {
+            let jwk: Key = serde_json::from_slice(pubkey)
+                .map_err(|e| KeyError::InvalidPublicKey(e.to_string()))?;
+            DecodingKey::from_rsa_components(&jwk.n, &jwk.e).into_static()
+        } else {
+            DecodingKey::from_rsa_pem(pubkey)
+                .map_err(|e| KeyError::InvalidPublicKey(e.to_string()))?
+                .into_static()
+        };
+
+        let keys = Self { encoding, decoding };
+        // Catch a mismatched pair now rather than on the first token.
+        let probe = encode(
+            &Header::new(Algorithm::RS256),
+            &serde_json::json!({}),
+            &keys.encoding,
+        )
+        .map_err(KeyError::InvalidPrivateKey)?;
+        let validation = Validation {
+            validate_exp: false,
+            ..Validation::new(Algorithm::RS256)
+        };
+        if decode::<serde_json::Value>(&probe, &keys.decoding, &validation).is_err() {
+            return Err(KeyError::Mismatch);
+        }
+        Ok(keys)
+    }
+
+    /// Load the configured key pair, inline keys taking precedence over
+    /// their paths. There are no keys when neither half is configured.
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, KeyError> {
+        let privkey = read_key(&settings.privkey, &settings.privkey_path)?;
+        let pubkey = read_key(&settings.pubkey, &settings.pubkey_path)?;
+        match (privkey, pubkey) {
+            (Some(privkey), Some(pubkey)) => Ok(Some(Self::new(&privkey, &pubkey)?)),
+            (None, None) => Ok(None),
+            _ => Err(KeyError::Incomplete),
+        }
+    }
+
+    pub fn sign(&self, claims: &Claims) -> Result<String, TokenError> {
+        encode(&Header::new(Algorithm::RS256), claims, &self.encoding).map_err(From::from)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<TokenData<Claims>, TokenError> {
+        verify_jwt_token_with_key(&self.decoding, token, &Validation::new(Algorithm::RS256))
+    }
+
+    /// The key pair checked in under `src/`, for tests only.
+    #[cfg(test)]
+    pub fn test_keys() -> Self {
+        Self::new(
+            include_bytes!("private_rsa_key.pem"),
+            include_bytes!("public_rsa_key.pem"),
+        )
+        .unwrap()
+    }
 }
 
-pub fn generate_token(my_claims: &Claims) -> Result<String, TokenError> {
-    let settings = settings::Settings::default();
-    let privkey_path = settings.privkey_path;
+fn read_key(inline: &Option<String>, path: &Option<String>) -> Result<Option<Vec<u8>>, KeyError> {
+    if let Some(ref key) = inline {
+        return Ok(Some(key.as_bytes().to_vec()));
+    }
+    match path {
+        Some(ref path) => std::fs::read(path)
+            .map(Some)
+            .map_err(|e| KeyError::Io(path.clone(), e)),
+        None => Ok(None),
+    }
+}
 
-    encode(
-        &Header::new(Algorithm::RS256),
-        &my_claims,
-        &EncodingKey::from_rsa_pem(read_from_file(&privkey_path).as_slice()).unwrap(),
-    )
-    .map_err(From::from)
+pub fn verify_jwt_token_with_key(
+    key: &DecodingKey<'_>,
+    token: &str,
+    validation: &Validation,
+) -> Result<TokenData<Claims>, TokenError> {
+    decode::<Claims>(token, key, validation).map_err(From::from)
 }
 
 #[cfg(test)]
@@ -94,23 +216,22 @@ mod tests {
     fn test_token() {
         static THREE_DAYS: i64 = 60 * 60 * 24 * 3;
         let now = Utc::now().timestamp_nanos() / 1_000_000_000; //nanoseconds -> seconds
-        let scope_vec: Vec<String> = vec![
-            "profile:write".to_string(),
-            "profile:email".to_string(),
-            "profile:email:write".to_string(),
-        ];
         let my_claims = Claims {
-            user: "dummy_user".to_string(),
-            scope: Some(scope_vec),
+            sub: "dummy_user".to_string(),
+            iss: "dummy_issuer".to_string(),
+            aud: vec![],
+            scope: "profile:write profile:email profile:email:write".to_string(),
             client_id: "bhj4".to_string(),
             iat: now,
             exp: now + THREE_DAYS,
-            issuer: "dummy_issuer".to_string(),
+            generation: Some(1234),
+            profile_changed_at: None,
         };
 
-        let token = generate_token(&my_claims).unwrap();
+        let keys = SigningKeys::test_keys();
+        let token = keys.sign(&my_claims).unwrap();
 
-        match verify_jwt_token(&token) {
+        match keys.verify(&token) {
             Ok(value) => {
                 assert!(value.claims == my_claims);
             }
@@ -121,6 +242,82 @@ mod tests {
     fn test_invalid_token() {
         let token: String = String::from("bhxkgadweahfjhaweglfvawjcj");
 
-        assert!(verify_jwt_token(&token).is_err());
+        assert!(SigningKeys::test_keys().verify(&token).is_err());
+    }
+
+    #[test]
+    fn test_signing_keys() {
+        let privkey = include_str!("private_rsa_key.pem");
+        let pubkey = include_str!("public_rsa_key.pem");
+        let settings = Settings {
+            privkey: Some(privkey.to_owned()),
+            pubkey_path: Some("src/public_rsa_key.pem".to_owned()),
+            ..Settings::default()
+        };
+        assert!(SigningKeys::from_settings(&settings).unwrap().is_some());
+        assert!(SigningKeys::from_settings(&Settings::default())
+            .unwrap()
+            .is_none());
+
+        // The public key can be a JWK too.
+        let jwk = r#"{"kty": "RSA", "n": "nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA-kzeVOVpVWwkWdVha4s38XM_pa_yr47av7-z3VTmvDRyAHcaT92whREFpLv9cj5lTeJSibyr_Mrm_YtjCZVWgaOYIhwrXwKLqPr_11inWsAkfIytvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0e-lf4s4OxQawWD79J9_5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWbV6L11BWkpzGXSW4Hv43qa-GSYOD2QU68Mb59oSk2OB-BtOLpJofmbGEGgvmwyCI9Mw", "e": "AQAB"}"#;
+        assert!(SigningKeys::new(privkey.as_bytes(), jwk.as_bytes()).is_ok());
+
+        let cases = vec![
+            (Some(privkey), None, None, "Incomplete"),
+            (Some(privkey), None, Some("no/such/key.pem"), "Io"),
+            (Some("potato"), Some(pubkey), None, "InvalidPrivateKey"),
+            (Some(privkey), Some("potato"), None, "InvalidPublicKey"),
+            (
+                Some(privkey),
+                Some(r#"{"n": "AQAB", "e": "AQAB"}"#),
+                None,
+                "Mismatch",
+            ),
+        ];
+        for (privkey, pubkey, pubkey_path, error) in cases {
+            let settings = Settings {
+                privkey: privkey.map(str::to_owned),
+                pubkey: pubkey.map(str::to_owned),
+                pubkey_path: pubkey_path.map(str::to_owned),
+                ..Settings::default()
+            };
+            let e = SigningKeys::from_settings(&settings).unwrap_err();
+            assert!(format!("{:?}", e).starts_with(error), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_claims() {
+        let claims: Claims = serde_json::from_str(
+            r#"{
+                "sub": "deadbeef",
+                "iss": "https://accounts.firefox.com",
+                "aud": "https://oauth.accounts.firefox.com",
+                "scope": "profile https://identity.mozilla.com/apps/oldsync",
+                "client_id": "5882386c6d801776",
+                "iat": 1600000000,
+                "exp": 1600086400,
+                "fxa-generation": 1599999999000,
+                "fxa-profileChangedAt": 1599999999001
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(claims.aud, vec!["https://oauth.accounts.firefox.com"]);
+        assert_eq!(
+            claims.scopes(),
+            vec!["profile", "https://identity.mozilla.com/apps/oldsync"]
+        );
+        assert_eq!(claims.generation, Some(1_599_999_999_000));
+        assert_eq!(claims.profile_changed_at, Some(1_599_999_999_001));
+
+        let claims: Claims = serde_json::from_str(
+            r#"{"sub": "deadbeef", "iss": "https://accounts.firefox.com", "aud": ["a", "b"],
+                "scope": "", "client_id": "5882386c6d801776", "iat": 0, "exp": 0}"#,
+        )
+        .unwrap();
+        assert_eq!(claims.aud, vec!["a", "b"]);
+        assert!(claims.scopes().is_empty());
+        assert_eq!(claims.generation, None);
     }
 }